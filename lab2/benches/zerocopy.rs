@@ -0,0 +1,125 @@
+//! Uploads a file over loopback through the buffered and the zero-copy
+//! data paths and prints the throughput of each. The size in MiB can be set
+//! with `LAB2_BENCH_MIB`, the default is 1024.
+//!
+//!     cargo bench --bench zerocopy
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+use lab2::bytes_to_hr;
+use lab2::client::Client;
+use lab2::hook::Hooks;
+use lab2::progress::ProgressMode;
+use lab2::server::Config;
+use lab2::server::Server;
+use lab2::ConflictPolicy;
+
+fn write_input(path: &Path, mib: usize) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut block = vec![0u8; 1024 * 1024];
+
+    // xorshift, so the data neither compresses nor dedups
+    let mut state = 0x2545f4914f6cdd1du64;
+
+    for _ in 0..mib {
+        for byte in block.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+
+        file.write_all(&block)?;
+    }
+
+    file.sync_all()
+}
+
+fn start_server(dir: &Path, zero_copy: bool) -> io::Result<SocketAddr> {
+    let config = Config {
+        root: dir.join("uploads"),
+        quarantine: dir.join("quarantine"),
+        conflict: ConflictPolicy::Overwrite,
+        tls: None,
+        accounts: None,
+        compression: Vec::new(),
+        limit: None,
+        connection_limit: None,
+        zero_copy,
+        handshake_timeout: None,
+        idle_timeout: None,
+        min_rate: None,
+        mode_mask: lab2::meta::DEFAULT_MODE_MASK,
+        xattrs: false,
+        max_file_size: None,
+        user_quota: None,
+        ip_quota: None,
+        dedup: false,
+        hooks: Hooks::default(),
+    };
+
+    let mut server = Server::new(config)?;
+    server.listen(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+
+    let port = server.local_addr()?.port();
+
+    thread::spawn(move || server.serve(1, 4));
+
+    Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+}
+
+fn upload(addr: SocketAddr, input: &Path, zero_copy: bool) -> io::Result<f64> {
+    let mut client = Client::connect(&[addr], None)?;
+    client.set_progress(ProgressMode::Quiet);
+    client.set_zero_copy(zero_copy);
+    client.hello()?;
+
+    let start = Instant::now();
+    client.transfer(input)?;
+
+    Ok(start.elapsed().as_secs_f64())
+}
+
+fn main() -> io::Result<()> {
+    let mib = env::var("LAB2_BENCH_MIB")
+        .ok()
+        .and_then(|mib| mib.parse().ok())
+        .unwrap_or(1024);
+
+    let dir = env::temp_dir().join(format!("lab2-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let input = dir.join("input.bin");
+    write_input(&input, mib)?;
+
+    let mut results = Vec::new();
+
+    for (label, zero_copy) in [("buffered", false), ("zero-copy", true)] {
+        let addr = start_server(&dir.join(label), zero_copy)?;
+
+        // once to warm up the page cache, once measured
+        upload(addr, &input, zero_copy)?;
+        let secs = upload(addr, &input, zero_copy)?;
+
+        results.push((label, secs));
+    }
+
+    for (label, secs) in results {
+        eprintln!(
+            "{label:>10}: {} in {secs:.2}s, {}/s",
+            bytes_to_hr((mib * 1024 * 1024) as f64),
+            bytes_to_hr((mib * 1024 * 1024) as f64 / secs)
+        );
+    }
+
+    fs::remove_dir_all(&dir)
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use argon2::PasswordHash;
+use argon2::PasswordHasher;
+use argon2::PasswordVerifier;
+
+use crate::server::sanitize_name;
+use crate::Credentials;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Password,
+    Token,
+}
+
+/// An entry of the credentials file. Each line has the form
+/// `name:kind:hash:permissions`, where `kind` is `password` or `token`,
+/// `hash` is an Argon2 PHC string (see `hash_secret`) and `permissions` is a
+/// comma separated subset of `read,write`. Blank lines and lines starting
+/// with `#` are ignored.
+#[derive(Clone)]
+pub struct Account {
+    pub name: String,
+    pub read: bool,
+    pub write: bool,
+    kind: Kind,
+    hash: String,
+}
+
+pub struct Accounts {
+    accounts: Vec<Account>,
+}
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("credentials line {line}: {message}"),
+    )
+}
+
+impl Accounts {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut accounts = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let [name, kind, hash, permissions] = line.splitn(4, ':').collect::<Vec<_>>()[..]
+            else {
+                return Err(invalid(i + 1, "expected name:kind:hash:permissions"));
+            };
+
+            if sanitize_name(name).is_none() {
+                return Err(invalid(i + 1, "name must be a valid file name"));
+            }
+
+            let kind = match kind {
+                "password" => Kind::Password,
+                "token" => Kind::Token,
+                _ => return Err(invalid(i + 1, "kind must be password or token")),
+            };
+
+            if PasswordHash::new(hash).is_err() {
+                return Err(invalid(i + 1, "hash is not a PHC string"));
+            }
+
+            let mut account = Account {
+                name: name.to_string(),
+                read: false,
+                write: false,
+                kind,
+                hash: hash.to_string(),
+            };
+
+            for permission in permissions.split(',').map(str::trim) {
+                match permission {
+                    "read" => account.read = true,
+                    "write" => account.write = true,
+                    "" => {}
+                    _ => return Err(invalid(i + 1, "permissions must be read and/or write")),
+                }
+            }
+
+            accounts.push(account);
+        }
+
+        Ok(Self { accounts })
+    }
+
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<&Account> {
+        let (kind, name, secret) = match credentials {
+            Credentials::Password { user, password } => (Kind::Password, Some(user), password),
+            Credentials::Token(token) => (Kind::Token, None, token),
+        };
+
+        self.accounts.iter().find(|account| {
+            account.kind == kind
+                && name.is_none_or(|name| *name == account.name)
+                && verify_secret(secret, &account.hash)
+        })
+    }
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+fn verify_secret(secret: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok()
+    })
+}
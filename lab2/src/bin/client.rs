@@ -1,36 +1,511 @@
-use args::Args;
-use clap::Parser;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
-use lab2::client::Client;
+use args::{Args, Command};
+use clap::{CommandFactory, Parser};
+
+use lab2::{
+    bytes_to_hr,
+    client::{Client, TransferError},
+    is_timeout,
+    progress::ProgressMode,
+    tls, to_hex, FileEntry,
+};
+use rustls::pki_types::ServerName;
+
+fn exit_code(err: &io::Error) -> i32 {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<TransferError>())
+        .map_or(1, TransferError::exit_code)
+}
+
+fn format_timestamp(secs: u64) -> String {
+    // days since epoch to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = secs % 86400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn print_listing(files: &[FileEntry]) {
+    for file in files {
+        println!(
+            "{:>10}  {}  {}  {}",
+            bytes_to_hr(file.len as f64),
+            format_timestamp(file.modified),
+            file.hash.as_deref().map_or_else(|| "-".to_string(), to_hex),
+            file.name
+        );
+    }
+}
+
+fn nonzero(timeout: Duration) -> Option<Duration> {
+    Some(timeout).filter(|timeout| !timeout.is_zero())
+}
+
+/// Socket timeouts come as a bare EAGAIN, say what actually happened.
+fn explain_timeout(err: io::Error, timeout: Duration) -> io::Error {
+    if is_timeout(&err) {
+        let message = format!("no response from the server for {}s", timeout.as_secs());
+        io::Error::new(err.kind(), message)
+    } else {
+        err
+    }
+}
+
+/// Connects to the server, then runs the TLS handshake and authentication if
+/// asked to. Errors come with what was being attempted.
+fn connect(args: &Args) -> Result<Client, (String, io::Error)> {
+    let connect = if args.udp {
+        Client::connect_udp
+    } else {
+        Client::connect
+    };
+
+    let mut client = connect(&args.dest.addrs, nonzero(args.connect_timeout))
+        .map_err(|err| (format!("error connecting to {}", args.dest), err))?;
+
+    client
+        .set_timeout(nonzero(args.timeout))
+        .map_err(|err| ("error setting timeouts".to_string(), err))?;
+
+    client.set_compression(args.compress.clone());
+
+    if args.quiet {
+        client.set_progress(ProgressMode::Quiet);
+    }
+
+    if let Some(rate) = args.limit {
+        client.set_limit(rate);
+    }
+
+    client.set_zero_copy(!args.no_zero_copy);
+    client.set_xattrs(args.xattrs);
+
+    if let Some(trust) = args.tls.trust(&args.dest) {
+        let identity = args.tls.cert.as_deref().zip(args.tls.key.as_deref());
+
+        let config = tls::client_config(trust, identity)
+            .map_err(|err| ("error loading tls configuration".to_string(), err))?;
+
+        let name = match &args.tls.server_name {
+            Some(name) => ServerName::try_from(name.clone()).map_err(|err| {
+                (
+                    format!("invalid server name {name}"),
+                    io::Error::new(io::ErrorKind::InvalidInput, err),
+                )
+            })?,
+            None => ServerName::try_from(args.dest.host().to_string()).map_err(|err| {
+                (
+                    format!("invalid server name {}", args.dest.host()),
+                    io::Error::new(io::ErrorKind::InvalidInput, err),
+                )
+            })?,
+        };
+
+        client.start_tls(config, name).map_err(|err| {
+            let err = explain_timeout(err, args.timeout);
+            (format!("tls handshake with {} failed", args.dest), err)
+        })?;
+    }
+
+    client.hello().map_err(|err| {
+        let err = explain_timeout(err, args.timeout);
+        (format!("error greeting {}", args.dest), err)
+    })?;
+
+    if let Some(credentials) = args.auth.credentials() {
+        client.authenticate(credentials).map_err(|err| {
+            let err = explain_timeout(err, args.timeout);
+            ("authentication failed".to_string(), err)
+        })?;
+    }
+
+    Ok(client)
+}
+
+fn run(client: &mut Client, command: Command, args: &Args) -> io::Result<()> {
+    match command {
+        Command::List => {
+            print_listing(&client.list()?);
+        }
+
+        Command::Get { name, output } => {
+            let output = output.unwrap_or_else(|| {
+                Path::new(&name)
+                    .file_name()
+                    .map_or_else(|| PathBuf::from("download"), PathBuf::from)
+            });
+
+            if !args.quiet {
+                println!("Connected to server, downloading {name}");
+            }
+
+            client.download(&name, &output)?;
+
+            if !args.quiet {
+                println!("Download complete!");
+            }
+        }
+
+        Command::Upload {
+            file,
+            include,
+            exclude,
+        } => {
+            if !args.quiet {
+                println!("Connected to server, transfering {file}");
+            }
+
+            let is_dir = file != "-" && Path::new(&file).is_dir();
+            // stdin, pipes and the like have no length to announce
+            let streamed = file == "-" || !is_dir && !fs::metadata(&file)?.is_file();
+
+            if is_dir {
+                client.transfer_tree(&file, &include, &exclude)?;
+            } else if streamed {
+                let name = args.name.clone().unwrap_or_else(|| {
+                    Path::new(&file)
+                        .file_name()
+                        .map_or_else(|| file.clone(), |name| name.to_string_lossy().to_string())
+                });
+
+                if file == "-" {
+                    client.transfer_stream(io::stdin().lock(), &name)?;
+                } else {
+                    client.transfer_stream(File::open(&file)?, &name)?;
+                }
+            } else if args.delta {
+                client.transfer_delta(&file)?;
+            } else if args.streams > 1 {
+                client.transfer_parallel(&file, args.streams, || {
+                    connect(args).map_err(|(context, err)| {
+                        eprintln!("{context}: {err}");
+                        err
+                    })
+                })?;
+            } else {
+                client.transfer(&file)?;
+            }
+
+            if !args.quiet {
+                println!("Transfer complete!");
+            }
+        }
+    }
+
+    Ok(())
+}
 
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    let command = match (args.command.take(), args.file.take()) {
+        (Some(command), _) => command,
+        (None, Some(file)) => Command::Upload {
+            file,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        },
+        (None, None) if args.name.is_some() => Command::Upload {
+            file: "-".to_string(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        },
+        (None, None) => Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "either --file, --name or a subcommand is required",
+            )
+            .exit(),
+    };
 
-    let mut client = Client::new().unwrap();
+    if matches!(&command, Command::Upload { file, .. } if file == "-") && args.name.is_none() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--name is required to upload stdin",
+            )
+            .exit();
+    }
 
-    client.connect(args.dest).unwrap();
-    println!("Connected to server, transfering {}", args.file);
+    let mut client = connect(&args).unwrap_or_else(|(context, err)| {
+        eprintln!("{context}: {err}");
+        process::exit(exit_code(&err));
+    });
 
-    client.transfer(&args.file).unwrap();
-    println!("Transfer complete!");
+    if let Err(err) = run(&mut client, command, &args) {
+        let err = explain_timeout(err, args.timeout);
+        eprintln!("transfer failed: {err}");
+        // closes a UDP session properly before exiting
+        drop(client);
+        process::exit(exit_code(&err));
+    }
 }
 
 mod args {
     use std::{
-        io,
+        fmt,
         net::{SocketAddr, ToSocketAddrs},
+        path::PathBuf,
+        time::Duration,
     };
 
+    use lab2::{tls::Trust, Codec, Credentials};
+
     #[derive(clap::Parser)]
+    #[command(
+        after_help = "Exit codes: 1 I/O error, 10 file too large, 11 invalid name, \
+        12 already exists, 13 disk full, 14 quota exceeded, 15 unauthorized, \
+        16 server busy, 17 internal server error, 18 file not found, 19 invalid range, \
+        20 checksum mismatch, 21 incompatible protocol, 22 rejected by validation"
+    )]
     pub struct Args {
+        /// File or directory to upload, - for stdin
         #[arg(long, short)]
-        pub file: String,
+        pub file: Option<String>,
 
-        #[arg(long, short, value_parser = parse_socket_addr)]
-        pub dest: SocketAddr,
+        /// Name to store stdin or a pipe as; without --file or a subcommand,
+        /// stdin is uploaded
+        #[arg(long, short)]
+        pub name: Option<String>,
+
+        /// Server to connect to as host:port, IPv6 addresses in brackets;
+        /// every address the host resolves to is tried in turn
+        #[arg(long, short, value_parser = parse_dest)]
+        pub dest: Dest,
+
+        /// Compression codecs to offer for uploads, most preferred first;
+        /// the server picks the first it supports
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Codec::Zstd, Codec::Gzip])]
+        pub compress: Vec<Codec>,
+
+        /// Cap on upload bandwidth, e.g. 10MiB/s
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub limit: Option<u64>,
+
+        /// Always send through a user-space buffer instead of sendfile(2)
+        #[arg(long)]
+        pub no_zero_copy: bool,
+
+        /// Send the user.* extended attributes of uploaded files along with
+        /// their mode and modification time
+        #[arg(long)]
+        pub xattrs: bool,
+
+        /// Give up on each server address after this long, 0 to wait as long
+        /// as the system does
+        #[arg(long, default_value = "10s", value_parser = lab2::parse_duration)]
+        pub connect_timeout: Duration,
+
+        /// Connect over UDP, with retransmission and congestion control done
+        /// by lab2, for links with high latency or loss
+        #[arg(long)]
+        pub udp: bool,
+
+        /// Give up when the server sends or takes no data for this long, 0
+        /// for no limit
+        #[arg(long, default_value = "2m", value_parser = lab2::parse_duration)]
+        pub timeout: Duration,
+
+        /// Only print errors, no progress
+        #[arg(long, short)]
+        pub quiet: bool,
+
+        /// Only send what changed in a file since the server's copy of it
+        #[arg(long, conflicts_with = "streams")]
+        pub delta: bool,
+
+        /// Upload single files over this many parallel connections
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=64))]
+        pub streams: u32,
+
+        #[command(flatten)]
+        pub tls: TlsArgs,
+
+        #[command(flatten)]
+        pub auth: AuthArgs,
+
+        #[command(subcommand)]
+        pub command: Option<Command>,
+    }
+
+    #[derive(clap::Args)]
+    pub struct AuthArgs {
+        /// User to authenticate as, with --password
+        #[arg(long, requires = "password", conflicts_with = "token")]
+        pub user: Option<String>,
+
+        #[arg(long, env = "LAB2_PASSWORD", hide_env_values = true, requires = "user")]
+        pub password: Option<String>,
+
+        /// Authenticate with an access token instead of a user and password
+        #[arg(long, env = "LAB2_TOKEN", hide_env_values = true)]
+        pub token: Option<String>,
+    }
+
+    impl AuthArgs {
+        pub fn credentials(&self) -> Option<Credentials> {
+            match (&self.user, &self.password, &self.token) {
+                (Some(user), Some(password), _) => Some(Credentials::Password {
+                    user: user.clone(),
+                    password: password.clone(),
+                }),
+                (None, _, Some(token)) => Some(Credentials::Token(token.clone())),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(clap::Args)]
+    pub struct TlsArgs {
+        /// Connect over TLS, trusting the server on first use unless --ca or
+        /// --fingerprint is given
+        #[arg(long)]
+        pub tls: bool,
+
+        /// Verify the server against the CA certificates in this PEM file
+        #[arg(long, conflicts_with = "fingerprint")]
+        pub ca: Option<PathBuf>,
+
+        /// Only accept a server certificate with this SHA-256 fingerprint (hex)
+        #[arg(long, value_parser = parse_fingerprint)]
+        pub fingerprint: Option<Fingerprint>,
+
+        /// Where fingerprints of servers trusted on first use are kept
+        #[arg(long, default_value = ".lab2_known_hosts")]
+        pub known_hosts: PathBuf,
+
+        /// Name to verify the server certificate against, defaults to the
+        /// destination address
+        #[arg(long)]
+        pub server_name: Option<String>,
+
+        /// PEM client certificate for servers requiring client authentication
+        #[arg(long, requires = "key")]
+        pub cert: Option<PathBuf>,
+
+        /// PEM private key for --cert
+        #[arg(long, requires = "cert")]
+        pub key: Option<PathBuf>,
+    }
+
+    impl TlsArgs {
+        /// `None` if TLS wasn't asked for.
+        pub fn trust(&self, dest: &Dest) -> Option<Trust> {
+            if let Some(ca) = &self.ca {
+                Some(Trust::Roots(ca.clone()))
+            } else if let Some(fingerprint) = &self.fingerprint {
+                Some(Trust::Fingerprint(fingerprint.0.clone()))
+            } else if self.tls || self.cert.is_some() {
+                Some(Trust::KnownHosts {
+                    path: self.known_hosts.clone(),
+                    host: dest.name.clone(),
+                })
+            } else {
+                None
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Fingerprint(Vec<u8>);
+
+    fn parse_fingerprint(hex: &str) -> Result<Fingerprint, String> {
+        let hex = hex.replace(':', "");
+
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("expected 64 hex digits".to_string());
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|err| err.to_string()))
+            .collect::<Result<_, _>>()
+            .map(Fingerprint)
+    }
+
+    #[derive(clap::Subcommand)]
+    pub enum Command {
+        /// Upload a file or a directory tree, same as --file; - uploads stdin
+        Upload {
+            file: String,
+
+            /// Only upload files matching this glob, relative to the directory
+            #[arg(long)]
+            include: Vec<String>,
+
+            /// Skip files matching this glob, relative to the directory
+            #[arg(long)]
+            exclude: Vec<String>,
+        },
+
+        /// List files stored on the server
+        List,
+
+        /// Download a file from the server
+        Get {
+            name: String,
+
+            /// Where to save the file, defaults to its name in the current directory
+            #[arg(long, short)]
+            output: Option<PathBuf>,
+        },
+    }
+
+    #[derive(Clone)]
+    pub struct Dest {
+        pub name: String,
+        pub addrs: Vec<SocketAddr>,
     }
 
-    fn parse_socket_addr(addr: &str) -> io::Result<SocketAddr> {
-        Ok(addr.to_socket_addrs()?.next().unwrap())
+    impl Dest {
+        /// The host part of the destination, without the port or IPv6 brackets.
+        pub fn host(&self) -> &str {
+            let host = self
+                .name
+                .rsplit_once(':')
+                .map_or(&*self.name, |(host, _)| host);
+            host.trim_start_matches('[').trim_end_matches(']')
+        }
+    }
+
+    impl fmt::Display for Dest {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&self.name)
+        }
+    }
+
+    fn parse_dest(dest: &str) -> Result<Dest, String> {
+        let addrs: Vec<_> = dest
+            .to_socket_addrs()
+            .map_err(|err| err.to_string())?
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(format!("{dest} did not resolve to any address"));
+        }
+
+        Ok(Dest {
+            name: dest.to_string(),
+            addrs,
+        })
     }
 }
@@ -0,0 +1,255 @@
+//! Forwards UDP between clients and a server, dropping and delaying
+//! packets on the way, to try the UDP transport on a bad link without one.
+//! Each client gets its own socket towards the server, so the server sees
+//! one address per client as it would without the shim.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::UNIX_EPOCH;
+
+use args::Args;
+use clap::Parser;
+
+/// Xorshift, plenty for picking packets to drop.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1).
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A packet waiting out its delay.
+struct Delayed {
+    at: Instant,
+    order: u64,
+    socket: Arc<UdpSocket>,
+    to: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    // earliest first out of the max-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+/// Decides the fate of each packet and hands the survivors to the thread
+/// sending them once their delay is up.
+struct Link {
+    loss: f64,
+    delay: Duration,
+    jitter: Duration,
+    rng: Mutex<Rng>,
+    order: Mutex<u64>,
+    delayed: mpsc::Sender<Delayed>,
+    dropped: Mutex<(u64, u64)>,
+}
+
+impl Link {
+    fn forward(&self, socket: &Arc<UdpSocket>, to: SocketAddr, bytes: &[u8]) {
+        let (drop, jitter) = {
+            let mut rng = self.rng.lock().unwrap();
+            (rng.fraction() < self.loss, rng.fraction())
+        };
+
+        {
+            let mut dropped = self.dropped.lock().unwrap();
+            dropped.1 += 1;
+
+            if drop {
+                dropped.0 += 1;
+                return;
+            }
+        }
+
+        let order = {
+            let mut order = self.order.lock().unwrap();
+            *order += 1;
+            *order
+        };
+
+        let _ = self.delayed.send(Delayed {
+            at: Instant::now() + self.delay + self.jitter.mul_f64(jitter),
+            order,
+            socket: socket.clone(),
+            to,
+            bytes: bytes.to_vec(),
+        });
+    }
+}
+
+fn send_delayed(delayed: mpsc::Receiver<Delayed>) {
+    let mut waiting = BinaryHeap::new();
+
+    loop {
+        let now = Instant::now();
+
+        while waiting.peek().is_some_and(|next: &Delayed| next.at <= now) {
+            let next = waiting.pop().unwrap();
+            let _ = next.socket.send_to(&next.bytes, next.to);
+        }
+
+        let received = match waiting.peek() {
+            Some(next) => delayed.recv_timeout(next.at - now),
+            None => delayed
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(packet) => waiting.push(packet),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+
+    let seed = args
+        .seed
+        .unwrap_or_else(|| UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos() as u64);
+
+    let (sender, delayed) = mpsc::channel();
+    thread::spawn(move || send_delayed(delayed));
+
+    let link = Arc::new(Link {
+        loss: args.loss,
+        delay: args.delay,
+        jitter: args.jitter,
+        // xorshift never leaves zero
+        rng: Mutex::new(Rng(seed | 1)),
+        order: Mutex::new(0),
+        delayed: sender,
+        dropped: Mutex::new((0, 0)),
+    });
+
+    let listener = Arc::new(UdpSocket::bind(args.listen)?);
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut reported = 0;
+
+    println!(
+        "forwarding {} to {}, {:.1}% loss, {}ms delay, {}ms jitter, seed {seed}",
+        args.listen,
+        args.dest,
+        args.loss * 100.,
+        args.delay.as_millis(),
+        args.jitter.as_millis()
+    );
+
+    loop {
+        let (len, client) = listener.recv_from(&mut buffer)?;
+
+        let upstream = match upstreams.get(&client) {
+            Some(upstream) => upstream.clone(),
+            None => {
+                let bind = match args.dest {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+
+                let upstream = Arc::new(UdpSocket::bind(bind)?);
+                println!("new client {client}");
+
+                let (link, listener, receiving) =
+                    (link.clone(), listener.clone(), upstream.clone());
+
+                thread::spawn(move || {
+                    let mut buffer = vec![0u8; 64 * 1024];
+
+                    while let Ok((len, _)) = receiving.recv_from(&mut buffer) {
+                        link.forward(&listener, client, &buffer[..len]);
+                    }
+                });
+
+                upstreams.insert(client, upstream.clone());
+                upstream
+            }
+        };
+
+        link.forward(&upstream, args.dest, &buffer[..len]);
+
+        let (dropped, total) = *link.dropped.lock().unwrap();
+
+        if total / 10000 > reported {
+            reported = total / 10000;
+            println!("{dropped} of {total} packets dropped");
+        }
+    }
+}
+
+mod args {
+    use std::{net::SocketAddr, time::Duration};
+
+    #[derive(clap::Parser)]
+    #[command(about = "Forwards UDP between lab2 clients and a server over a simulated lossy link")]
+    pub struct Args {
+        /// Address clients connect to
+        #[arg(long, default_value = "127.0.0.1:7124")]
+        pub listen: SocketAddr,
+
+        /// Server to forward to
+        #[arg(long, default_value = "127.0.0.1:7123")]
+        pub dest: SocketAddr,
+
+        /// Fraction of packets dropped in each direction, e.g. 0.05
+        #[arg(long, default_value_t = 0., value_parser = parse_fraction)]
+        pub loss: f64,
+
+        /// Delay added to each packet in each direction, e.g. 50ms
+        #[arg(long, default_value = "0", value_parser = lab2::parse_duration)]
+        pub delay: Duration,
+
+        /// Up to this much more delay, picked at random for each packet, so
+        /// packets get reordered
+        #[arg(long, default_value = "0", value_parser = lab2::parse_duration)]
+        pub jitter: Duration,
+
+        /// Seed for the random drops and jitter, to repeat a run
+        #[arg(long)]
+        pub seed: Option<u64>,
+    }
+
+    fn parse_fraction(text: &str) -> Result<f64, String> {
+        text.parse()
+            .ok()
+            .filter(|fraction| (0. ..1.).contains(fraction))
+            .ok_or_else(|| format!("invalid fraction {text:?}, expected 0 up to 1"))
+    }
+}
@@ -1,40 +1,278 @@
-use std::thread;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use args::Args;
 use clap::Parser;
 
-use lab2::{format_sockaddr, server::Server};
+use lab2::{
+    auth::{self, Accounts},
+    bytes_to_hr, dedup,
+    hook::Hooks,
+    server::{Config, Server},
+    tls, to_hex,
+};
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    let server = Server::new().unwrap_or_else(|err| {
+    if args.hash_secret {
+        let mut secret = String::new();
+        io::stdin().read_line(&mut secret)?;
+        println!(
+            "{}",
+            auth::hash_secret(secret.trim_end_matches(['\r', '\n']))
+        );
+        return Ok(());
+    }
+
+    if args.gc {
+        let (objects, bytes) = dedup::collect_garbage(&args.root)?;
+        println!(
+            "removed {objects} unreferenced objects, {} freed",
+            bytes_to_hr(bytes as f64)
+        );
+        return Ok(());
+    }
+
+    let accounts = args.credentials.as_ref().map(|path| {
+        Accounts::load(path).unwrap_or_else(|err| {
+            panic!("error loading credentials from {}: {err}", path.display());
+        })
+    });
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let config =
+                tls::server_config(cert, key, args.client_ca.as_deref()).unwrap_or_else(|err| {
+                    panic!("error loading tls configuration: {err}");
+                });
+
+            if let Some(cert) = tls::load_certs(cert)?.first() {
+                println!(
+                    "tls enabled, certificate fingerprint {}",
+                    to_hex(&tls::fingerprint(cert))
+                );
+            }
+
+            Some(config)
+        }
+        _ => None,
+    };
+
+    let config = Config {
+        root: args.root,
+        quarantine: args.quarantine,
+        conflict: args.on_conflict,
+        tls,
+        accounts,
+        compression: args.compression,
+        limit: args.limit,
+        connection_limit: args.connection_limit,
+        zero_copy: !args.no_zero_copy,
+        handshake_timeout: Some(args.handshake_timeout).filter(|timeout| !timeout.is_zero()),
+        idle_timeout: Some(args.idle_timeout).filter(|timeout| !timeout.is_zero()),
+        min_rate: args.min_rate,
+        mode_mask: args.mode_mask,
+        xattrs: args.xattrs,
+        max_file_size: args.max_file_size,
+        user_quota: args.user_quota,
+        ip_quota: args.ip_quota,
+        dedup: args.dedup,
+        hooks: Hooks {
+            validate: args.validate_hook,
+            post_upload: args.post_upload_hook,
+            notify_pipe: args.notify_pipe,
+            timeout: args.hook_timeout,
+        },
+    };
+
+    let mut server = Server::new(config).unwrap_or_else(|err| {
         panic!("error creating server: {err}");
     });
 
-    server.listen(args.port).unwrap_or_else(|err| {
+    let listening = match args.bind {
+        Some(ip) => listen(&mut server, SocketAddr::new(ip, args.port), args.udp),
+        // dual-stack where available, IPv4 only on hosts without IPv6
+        None => listen(
+            &mut server,
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), args.port),
+            args.udp,
+        )
+        .or_else(|_| {
+            listen(
+                &mut server,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), args.port),
+                args.udp,
+            )
+        }),
+    };
+
+    listening.unwrap_or_else(|err| {
         panic!("error listening: {err}");
     });
 
-    loop {
-        let mut conn = server.accept().unwrap_or_else(|err| {
-            panic!("error accepting connections: {err}");
-        });
+    server.serve(args.workers, args.queue)
+}
 
-        println!("new connection: {}", format_sockaddr(&conn.addr));
+fn listen(server: &mut Server, addr: SocketAddr, udp: bool) -> io::Result<()> {
+    server.listen(addr)?;
 
-        thread::spawn(move || {
-            if let Err(err) = conn.transfer() {
-                eprintln!("error while transfering: {err}");
-            }
-        });
+    if udp {
+        server.listen_udp(addr)?;
     }
+
+    Ok(())
 }
 
 mod args {
+    use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+    use lab2::{Codec, ConflictPolicy};
+
     #[derive(clap::Parser)]
     pub struct Args {
         #[arg(long, short, default_value_t = 7123)]
         pub port: u16,
+
+        /// Address to listen on, defaults to all IPv6 and IPv4 addresses
+        #[arg(long)]
+        pub bind: Option<IpAddr>,
+
+        /// Directory uploaded files are stored in
+        #[arg(long, default_value = "uploads")]
+        pub root: PathBuf,
+
+        /// Directory files failing the checksum are moved to
+        #[arg(long, default_value = "quarantine")]
+        pub quarantine: PathBuf,
+
+        /// What to do when an uploaded file name already exists
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Rename)]
+        pub on_conflict: ConflictPolicy,
+
+        /// Compression codecs clients may use for uploads
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Codec::Zstd, Codec::Gzip])]
+        pub compression: Vec<Codec>,
+
+        /// Cap on upload bandwidth over all connections, e.g. 50MiB/s
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub limit: Option<u64>,
+
+        /// Cap on upload bandwidth per connection, e.g. 10MiB/s
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub connection_limit: Option<u64>,
+
+        /// Largest file accepted, e.g. 4GiB
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub max_file_size: Option<u64>,
+
+        /// Total bytes each authenticated user may upload
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub user_quota: Option<u64>,
+
+        /// Total bytes each client address may upload
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub ip_quota: Option<u64>,
+
+        /// Store each distinct content once, with file names as hard links to
+        /// it; files with the same content share their metadata
+        #[arg(long)]
+        pub dedup: bool,
+
+        /// Shell command each upload must pass before it is stored, failing
+        /// ones are quarantined; it gets LAB2_PATH, LAB2_NAME, LAB2_SIZE,
+        /// LAB2_SHA256, LAB2_CLIENT and LAB2_USER in its environment
+        #[arg(long)]
+        pub validate_hook: Option<String>,
+
+        /// Shell command run after each upload is stored, with the same
+        /// environment as --validate-hook; may be given several times
+        #[arg(long)]
+        pub post_upload_hook: Vec<String>,
+
+        /// Named pipe to write a line to for each upload stored: name, size,
+        /// sha256, client, user and path, separated by tabs
+        #[arg(long)]
+        pub notify_pipe: Option<PathBuf>,
+
+        /// Kill hooks still running after this long
+        #[arg(long, default_value = "30s", value_parser = lab2::parse_duration)]
+        pub hook_timeout: Duration,
+
+        /// Remove stored content no file links to any more and exit
+        #[arg(long)]
+        pub gc: bool,
+
+        /// Maximum number of connections handled at the same time; a
+        /// parallel upload holds one for itself while its streams come in,
+        /// so it may use at most one stream fewer
+        #[arg(long, default_value_t = 16)]
+        pub workers: usize,
+
+        /// Connections waiting for a free worker before new ones are
+        /// refused as busy
+        #[arg(long, default_value_t = 16)]
+        pub queue: usize,
+
+        /// Also take clients over UDP on the same port, for links with high
+        /// latency or loss
+        #[arg(long)]
+        pub udp: bool,
+
+        /// Always receive through a user-space buffer instead of splice(2)
+        #[arg(long)]
+        pub no_zero_copy: bool,
+
+        /// Time clients get to complete the TLS handshake, hello and
+        /// authentication and send their request, 0 for no limit
+        #[arg(long, default_value = "30s", value_parser = lab2::parse_duration)]
+        pub handshake_timeout: Duration,
+
+        /// Drop connections that send or take no data for this long, 0 for
+        /// no limit
+        #[arg(long, default_value = "2m", value_parser = lab2::parse_duration)]
+        pub idle_timeout: Duration,
+
+        /// Drop uploads arriving slower than this over 10 seconds, e.g. 1KiB/s
+        #[arg(long, value_parser = lab2::hr_to_bytes)]
+        pub min_rate: Option<u64>,
+
+        /// Mode bits uploads may set, in octal; the default strips setuid,
+        /// setgid and sticky bits
+        #[arg(long, default_value = "777", value_parser = parse_mode)]
+        pub mode_mask: u32,
+
+        /// Apply the user.* extended attributes clients send with uploads
+        #[arg(long)]
+        pub xattrs: bool,
+
+        /// PEM certificate chain, enables TLS together with --tls-key
+        #[arg(long, requires = "tls_key")]
+        pub tls_cert: Option<PathBuf>,
+
+        /// PEM private key for --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        pub tls_key: Option<PathBuf>,
+
+        /// Require clients to present a certificate signed by this PEM CA
+        #[arg(long, requires = "tls_cert")]
+        pub client_ca: Option<PathBuf>,
+
+        /// Require clients to authenticate against this credentials file,
+        /// with lines of the form name:password|token:hash:read,write
+        #[arg(long)]
+        pub credentials: Option<PathBuf>,
+
+        /// Read a password or token from stdin, print its hash for the
+        /// credentials file and exit
+        #[arg(long)]
+        pub hash_secret: bool,
+    }
+
+    fn parse_mode(mode: &str) -> Result<u32, String> {
+        u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("invalid mode {mode:?}, expected octal up to 7777"))
     }
 }
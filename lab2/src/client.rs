@@ -1,91 +1,1249 @@
+use std::error;
+use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::mem::MaybeUninit;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use rustls::ClientConnection;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use serde_binary::binary_stream::Endian;
+use sha2::Digest;
+use sha2::Sha256;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::SockAddr;
 use socket2::Socket;
 use socket2::Type;
 
+use crate::bytes_to_hr;
+use crate::compress::ChunkWriter;
+use crate::compress::Encoder;
+use crate::delta::Delta;
+use crate::hash_prefix;
+use crate::limit::RateLimit;
+use crate::limit::Throttled;
+use crate::meta;
+use crate::progress::Progress;
+use crate::progress::ProgressMode;
+use crate::read_message;
+use crate::tls::Stream;
+use crate::to_hex;
+use crate::udp;
+use crate::write_message;
+use crate::zerocopy;
+use crate::AuthResponse;
+use crate::Capability;
+use crate::Codec;
+use crate::ConflictPolicy;
+use crate::Credentials;
+use crate::DeltaComplete;
+use crate::DeltaOp;
+use crate::DeltaSignature;
+use crate::DownloadResponse;
+use crate::FileEntry;
+use crate::Hello;
+use crate::HelloResponse;
+use crate::ListResponse;
+use crate::ManifestEntry;
+use crate::ManifestResponse;
+use crate::RangeRequest;
+use crate::RangeResponse;
+use crate::Rejection;
+use crate::Request;
+use crate::ResumeDecision;
+use crate::StreamComplete;
+use crate::StreamRequest;
 use crate::TransferComplete;
+use crate::TransferDigest;
+use crate::Verdict;
+use crate::MIN_PROTOCOL_VERSION;
+use crate::PROTOCOL_VERSION;
 
 use super::TransferRequest;
 use super::TransferResponse;
 
+#[derive(Debug)]
+pub enum TransferError {
+    Rejected { reason: Rejection, message: String },
+    ChecksumMismatch,
+}
+
+impl TransferError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TransferError::Rejected { reason, .. } => reason.exit_code(),
+            TransferError::ChecksumMismatch => 20,
+        }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Rejected { reason, message } => {
+                write!(f, "rejected by server ({reason}): {message}")
+            }
+            TransferError::ChecksumMismatch => write!(f, "sha256 mismatch"),
+        }
+    }
+}
+
+impl error::Error for TransferError {}
+
+fn is_rejection(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|err| err.downcast_ref::<TransferError>().is_some())
+}
+
+/// Turns the server rejecting an upload into the error reported for it.
+fn accepted(verdict: Verdict) -> io::Result<()> {
+    match verdict {
+        Verdict::Accepted => Ok(()),
+        Verdict::Rejected { reason, message } => Err(io::Error::other(TransferError::Rejected {
+            reason,
+            message,
+        })),
+    }
+}
+
+fn build_globs(patterns: &[String]) -> io::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        builder.add(glob);
+    }
+
+    builder
+        .build()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Collects `(path, relative path, length)` of the regular files under `dir`,
+/// in a stable order. Symlinks are not followed.
+fn collect_files(
+    dir: &Path,
+    relative: &str,
+    files: &mut Vec<(PathBuf, String, u64)>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let path = if relative.is_empty() {
+            name
+        } else {
+            format!("{relative}/{name}")
+        };
+
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            collect_files(&entry.path(), &path, files)?;
+        } else if file_type.is_file() {
+            files.push((entry.path(), path, entry.metadata()?.len()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies `len` bytes from `file` to `writer`, hashing them on the way.
+fn send_hashed<W: Write>(
+    file: &mut File,
+    len: u64,
+    hasher: &mut Sha256,
+    writer: &mut W,
+    progress: &mut Progress,
+) -> io::Result<()> {
+    let mut buffer = [0u8; 8192];
+    let mut bytes_sent = 0;
+
+    while bytes_sent < len {
+        let chunk = buffer.len().min((len - bytes_sent) as usize);
+        let read = file.read(&mut buffer[..chunk])?;
+
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        writer.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+
+        bytes_sent += read as u64;
+        progress.add(read as u64);
+    }
+
+    progress.finish();
+
+    Ok(())
+}
+
+/// Copies `source` to `writer` until it ends, hashing it on the way.
+/// Returns false if the server stopped reading or spoke up first, which in
+/// the middle of an upload means it refused the rest.
+fn send_stream<R: Read, W: Write>(
+    source: &mut R,
+    hasher: &mut Sha256,
+    writer: &mut W,
+    progress: &mut Progress,
+    socket: &Socket,
+) -> io::Result<bool> {
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => return Ok(true),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        if writer.write_all(&buffer[..read]).is_err() || has_pending(socket) {
+            return Ok(false);
+        }
+
+        hasher.update(&buffer[..read]);
+        progress.add(read as u64);
+    }
+}
+
+/// Whether the server has sent something, or closed the connection,
+/// without waiting for it.
+fn has_pending(socket: &Socket) -> bool {
+    let mut byte = [MaybeUninit::uninit()];
+
+    match socket.recv_with_flags(&mut byte, libc::MSG_PEEK | libc::MSG_DONTWAIT) {
+        Ok(_) => true,
+        Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+    }
+}
+
 pub struct Client {
-    pub(crate) socket: Socket,
+    pub(crate) stream: Stream<ClientConnection>,
+    pub(crate) codecs: Vec<Codec>,
+    /// Agreed on in the hello.
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) progress: ProgressMode,
+    pub(crate) limit: Option<Arc<RateLimit>>,
+    pub(crate) zero_copy: bool,
+    pub(crate) xattrs: bool,
+    /// Set over UDP, last so that it's dropped after the stream.
+    pub(crate) _linger: Option<udp::Linger>,
 }
 
 impl Client {
-    pub fn new() -> io::Result<Self> {
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-        Ok(Self { socket })
+    /// Connects to the first of `addrs` that accepts, IPv4 or IPv6, giving
+    /// each at most `timeout`.
+    pub fn connect(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<Self> {
+        let mut last_error = None;
+
+        for addr in addrs {
+            let socket = Socket::new(
+                Domain::for_address(*addr),
+                Type::STREAM,
+                Some(Protocol::TCP),
+            )
+            .and_then(|socket| {
+                let addr = SockAddr::from(*addr);
+
+                match timeout {
+                    Some(timeout) => socket.connect_timeout(&addr, timeout)?,
+                    None => socket.connect(&addr)?,
+                }
+
+                Ok(socket)
+            });
+
+            match socket {
+                Ok(socket) => return Ok(Self::new(socket)),
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        }))
+    }
+
+    /// Like `connect`, but over the server's UDP transport, for links with
+    /// high latency or loss.
+    pub fn connect_udp(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<Self> {
+        let mut last_error = None;
+
+        for addr in addrs {
+            match udp::connect(*addr, timeout) {
+                Ok((socket, linger)) => {
+                    return Ok(Self {
+                        _linger: Some(linger),
+                        ..Self::new(socket)
+                    })
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        }))
+    }
+
+    fn new(socket: Socket) -> Self {
+        Self {
+            stream: Stream::new(socket),
+            codecs: Vec::new(),
+            capabilities: Vec::new(),
+            progress: ProgressMode::detect(),
+            limit: None,
+            zero_copy: true,
+            xattrs: false,
+            _linger: None,
+        }
+    }
+
+    /// Fails reads and writes that block for longer than `timeout`, e.g.
+    /// when the server stops responding.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.socket.set_read_timeout(timeout)?;
+        self.stream.socket.set_write_timeout(timeout)
+    }
+
+    /// Whether uncompressed uploads without TLS are sent with `sendfile`.
+    pub fn set_zero_copy(&mut self, zero_copy: bool) {
+        self.zero_copy = zero_copy;
     }
 
-    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
-        self.socket.connect(&SockAddr::from(addr))
+    /// Whether the `user.` extended attributes of uploaded files are sent
+    /// along with their mode and modification time.
+    pub fn set_xattrs(&mut self, xattrs: bool) {
+        self.xattrs = xattrs;
     }
 
-    fn send<T: Serialize>(&mut self, value: &T) -> io::Result<usize> {
-        self.socket
-            .write(&serde_binary::to_vec(value, Endian::Big).unwrap())
+    /// Caps uploads at `rate` bytes per second, counted on the wire. The
+    /// connections of a parallel upload share the cap.
+    pub fn set_limit(&mut self, rate: u64) {
+        self.limit = Some(Arc::new(RateLimit::new(rate)));
+    }
+
+    /// With `ProgressMode::Quiet`, only errors are printed.
+    pub fn set_progress(&mut self, progress: ProgressMode) {
+        self.progress = progress;
+    }
+
+    fn info(&self, message: fmt::Arguments) {
+        if self.progress != ProgressMode::Quiet {
+            println!("{message}");
+        }
+    }
+
+    /// Codecs offered to the server for uploads, most preferred first. The
+    /// server picks one, or none if it supports none of them.
+    pub fn set_compression(&mut self, codecs: Vec<Codec>) {
+        self.codecs = codecs;
+    }
+
+    /// Runs a TLS handshake on the connected socket; everything sent
+    /// afterwards is encrypted.
+    pub fn start_tls(
+        &mut self,
+        config: Arc<ClientConfig>,
+        name: ServerName<'static>,
+    ) -> io::Result<()> {
+        let mut conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+
+        while conn.is_handshaking() {
+            conn.complete_io(&mut self.stream.socket)?;
+        }
+
+        self.stream.tls = Some(conn);
+
+        Ok(())
+    }
+
+    fn send<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        write_message(&mut self.stream, value)
     }
 
     fn recv<T: DeserializeOwned>(&mut self) -> io::Result<T> {
-        let mut buffer = [0u8; 8192];
-        let read = self.socket.read(&mut buffer)?;
-        Ok(serde_binary::from_slice(&buffer[..read], Endian::Big).unwrap())
+        read_message(&mut self.stream)
     }
 
-    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
-        self.socket.write(buffer)
+    /// Opens the conversation, after the TLS handshake if any and before
+    /// anything else. Features the server lacks are left unused.
+    pub fn hello(&mut self) -> io::Result<()> {
+        self.send(&Hello::new(&Capability::ALL))?;
+
+        let response = self.recv().map_err(|err| {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                io::Error::new(
+                    err.kind(),
+                    "server closed the connection, it may be too old for this client",
+                )
+            } else {
+                err
+            }
+        })?;
+
+        match response {
+            HelloResponse::Accepted {
+                version,
+                capabilities,
+            } if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                self.capabilities = Capability::parse(&capabilities);
+                Ok(())
+            }
+            HelloResponse::Accepted { version, .. } => {
+                Err(io::Error::other(TransferError::Rejected {
+                    reason: Rejection::Incompatible,
+                    message: format!("server speaks unsupported protocol version {version}"),
+                }))
+            }
+            HelloResponse::Failure { reason, message } => {
+                Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }))
+            }
+        }
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// The server's verdict on the upload whose digest was just sent, if it
+    /// gives one. The complete message follows either way.
+    fn recv_verdict(&mut self) -> io::Result<Verdict> {
+        if self.supports(Capability::Validation) {
+            self.recv()
+        } else {
+            Ok(Verdict::Accepted)
+        }
+    }
+
+    /// Codecs to offer in a transfer request, none unless the server can
+    /// take compressed uploads.
+    fn offered_codecs(&self) -> Vec<Codec> {
+        if self.supports(Capability::Compression) {
+            self.codecs.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Authenticates before the request; returns the name the server knows
+    /// us by.
+    pub fn authenticate(&mut self, credentials: Credentials) -> io::Result<String> {
+        self.send(&Request::Authenticate(credentials))?;
+
+        match self.recv()? {
+            AuthResponse::Success { user } => Ok(user),
+            AuthResponse::Failure { reason, message } => {
+                Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }))
+            }
+        }
+    }
+
+    pub fn list(&mut self) -> io::Result<Vec<FileEntry>> {
+        self.send(&Request::List)?;
+
+        match self.recv()? {
+            ListResponse::Success { files } => Ok(files),
+            ListResponse::Failure { reason, message } => {
+                Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }))
+            }
+        }
+    }
+
+    /// Downloads `name` into `path`, checking it against the hash the server
+    /// recorded at upload time if there is one. Returns the number of bytes
+    /// received.
+    pub fn download<P: AsRef<Path>>(&mut self, name: &str, path: P) -> io::Result<u64> {
+        self.send(&Request::Download {
+            name: name.to_string(),
+        })?;
+
+        let (len, expected) = match self.recv()? {
+            DownloadResponse::Success { len, hash } => (len, hash),
+            DownloadResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+        };
+
+        let mut out = File::create(path.as_ref())?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 8192];
+        let mut bytes_rcvd = 0;
+        let mut progress = Progress::new(self.progress, len, 0);
+
+        while bytes_rcvd < len {
+            let chunk = buffer.len().min((len - bytes_rcvd) as usize);
+            let rcvd = self.stream.read(&mut buffer[..chunk])?;
+
+            if rcvd == 0 {
+                drop(out);
+                let _ = fs::remove_file(path.as_ref());
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            out.write_all(&buffer[..rcvd])?;
+            hasher.update(&buffer[..rcvd]);
+
+            bytes_rcvd += rcvd as u64;
+            progress.add(rcvd as u64);
+        }
+
+        progress.finish();
+
+        let hash = hasher.finalize().to_vec();
+
+        if expected.is_some_and(|expected| expected != hash) {
+            drop(out);
+            let _ = fs::remove_file(path.as_ref());
+            return Err(io::Error::other(TransferError::ChecksumMismatch));
+        }
+
+        self.info(format_args!(
+            "complete. bytes received: {}, sha256: {}",
+            bytes_rcvd,
+            to_hex(&hash)
+        ));
+
+        Ok(bytes_rcvd)
     }
 
     pub fn transfer<P: AsRef<Path>>(&mut self, file: P) -> io::Result<()> {
         let mut out = File::open(file.as_ref())?;
         let len = out.metadata()?.len();
 
-        let mut buffer = [0u8; 8192];
+        let Some(filename) = file.as_ref().file_name() else {
+            eprintln!("invalid file name");
+            return Ok(());
+        };
+
+        let request = TransferRequest::new(
+            filename.to_string_lossy().to_string(),
+            len,
+            self.offered_codecs(),
+            Some(meta::read(&out, self.xattrs)?),
+        );
+
+        // one more pass over the file, for the chance of sending none of it
+        if self.supports(Capability::Dedup) {
+            let hash = hash_prefix(&mut out, len)?.finalize().to_vec();
+            self.send(&Request::UploadHashed { request, hash })?;
+        } else {
+            self.send(&Request::Upload(request))?;
+        }
+
+        self.upload(out, len)
+    }
+
+    /// Uploads `file` as a delta against the server's copy of it: only the
+    /// data that copy lacks is sent, the rest is referred to by block. Falls
+    /// back to a plain upload if the server can't do deltas.
+    pub fn transfer_delta<P: AsRef<Path>>(&mut self, file: P) -> io::Result<()> {
+        if !self.supports(Capability::Delta) {
+            self.info(format_args!(
+                "server does not support delta uploads, sending the whole file"
+            ));
+            return self.transfer(file);
+        }
+
+        let mut out = File::open(file.as_ref())?;
+        let len = out.metadata()?.len();
 
         let Some(filename) = file.as_ref().file_name() else {
             eprintln!("invalid file name");
             return Ok(());
         };
 
-        self.send(&TransferRequest::new(
+        self.send(&Request::UploadDelta(TransferRequest::new(
             filename.to_string_lossy().to_string(),
             len,
-        ))?;
+            Vec::new(),
+            Some(meta::read(&out, self.xattrs)?),
+        )))?;
 
-        let response: TransferResponse = self.recv()?;
+        match self.recv()? {
+            TransferResponse::Success { name, conflict, .. } => {
+                self.report_conflict(&name, conflict)
+            }
+            TransferResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected response to a delta upload",
+                ));
+            }
+        }
 
-        match response {
-            TransferResponse::Success => {}
-            TransferResponse::Failure => {
-                eprintln!("received error from server");
-                return Ok(());
+        let signature: DeltaSignature = self.recv()?;
+
+        if signature.blocks.is_empty() {
+            self.info(format_args!(
+                "no copy on the server, sending the whole file"
+            ));
+        }
+
+        let delta = Delta::new(signature.blocks, signature.block_size);
+        let mut hasher = Sha256::new();
+        let mut progress = Progress::new(self.progress, len, 0);
+        let mut stream = Throttled::new(&mut self.stream, self.limit.clone());
+        let mut done = 0;
+
+        delta.diff(&mut out, &mut hasher, |op| {
+            done += match &op {
+                DeltaOp::Copy { count, .. } => count * signature.block_size,
+                DeltaOp::Literal(data) => data.len() as u64,
+                DeltaOp::End => 0,
+            };
+            progress.set(done.min(len));
+
+            write_message(&mut stream, &op)
+        })?;
+
+        write_message(&mut stream, &DeltaOp::End)?;
+        progress.finish();
+
+        let hash = hasher.finalize().to_vec();
+        self.send(&TransferDigest::new(hash.clone()))?;
+
+        let verdict = self.recv_verdict()?;
+        let complete: DeltaComplete = self.recv()?;
+        accepted(verdict)?;
+
+        if !complete.verified {
+            return Err(io::Error::other(TransferError::ChecksumMismatch));
+        }
+
+        self.info(format_args!(
+            "complete. bytes transfered: {}, {} reused from the server's copy ({:.1}%), sha256: {}",
+            complete.len - complete.reused,
+            complete.reused,
+            100. * complete.reused as f64 / complete.len.max(1) as f64,
+            to_hex(&hash)
+        ));
+
+        Ok(())
+    }
+
+    /// Uploads whatever `source` yields until it ends as `name`, for data
+    /// whose length isn't known up front such as a pipe. It goes out in
+    /// chunks, so such uploads can't be resumed.
+    pub fn transfer_stream<R: Read>(&mut self, mut source: R, name: &str) -> io::Result<()> {
+        if !self.supports(Capability::Stream) {
+            return Err(io::Error::other(TransferError::Rejected {
+                reason: Rejection::Incompatible,
+                message: "server does not support uploads of unknown length".to_string(),
+            }));
+        }
+
+        self.send(&Request::UploadStream(StreamRequest {
+            name: name.to_string(),
+            codecs: self.offered_codecs(),
+            metadata: None,
+        }))?;
+
+        let codec = match self.recv()? {
+            TransferResponse::Success {
+                name,
+                conflict,
+                codec,
+            } => {
+                self.report_conflict(&name, conflict);
+                codec
+            }
+            TransferResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected response to a stream upload",
+                ));
+            }
+        };
+
+        let socket = self.stream.socket.try_clone()?;
+        let mut hasher = Sha256::new();
+        let mut progress = Progress::unbounded(self.progress);
+        let mut stream = Throttled::new(&mut self.stream, self.limit.clone());
+        let mut chunks = ChunkWriter::new(&mut stream);
+
+        let finished = if codec == Codec::None {
+            send_stream(
+                &mut source,
+                &mut hasher,
+                &mut chunks,
+                &mut progress,
+                &socket,
+            )?
+            .then(|| chunks.finish())
+        } else {
+            let mut encoder = Encoder::new(codec, chunks)?;
+            send_stream(
+                &mut source,
+                &mut hasher,
+                &mut encoder,
+                &mut progress,
+                &socket,
+            )?
+            .then(|| encoder.finish().and_then(ChunkWriter::finish))
+        };
+
+        progress.finish();
+
+        // otherwise the server cut the upload short, and says why below
+        let (wire, verdict) = match finished {
+            Some(Ok(wire)) => {
+                self.send(&TransferDigest::new(hasher.finalize().to_vec()))?;
+                (Some(wire), self.recv_verdict()?)
+            }
+            _ => (None, Verdict::Accepted),
+        };
+
+        let complete = match self.recv()? {
+            StreamComplete::Success(complete) => complete,
+            StreamComplete::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+        };
+
+        accepted(verdict)?;
+
+        if !complete.verified {
+            return Err(io::Error::other(TransferError::ChecksumMismatch));
+        }
+
+        if let Some(wire) = wire.filter(|_| codec != Codec::None) {
+            self.info(format_args!(
+                "compressed {} to {} with {codec}",
+                bytes_to_hr(complete.len as f64),
+                bytes_to_hr(wire as f64)
+            ));
+        }
+
+        self.info(format_args!("complete. bytes transfered: {}", complete.len));
+
+        Ok(())
+    }
+
+    /// Uploads `file` split into `streams` ranges, each sent over its own
+    /// connection opened by `connect`. This connection carries the request
+    /// and, once every range has arrived, the digest. Parallel uploads can't
+    /// be resumed and aren't compressed.
+    pub fn transfer_parallel<P, F>(&mut self, file: P, streams: u32, connect: F) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+        F: Fn() -> io::Result<Client> + Sync,
+    {
+        let path = file.as_ref();
+
+        if !self.supports(Capability::Parallel) {
+            self.info(format_args!(
+                "server does not support parallel uploads, using one connection"
+            ));
+            return self.transfer(path);
+        }
+
+        let mut out = File::open(path)?;
+        let len = out.metadata()?.len();
+
+        let Some(filename) = path.file_name() else {
+            eprintln!("invalid file name");
+            return Ok(());
+        };
+
+        self.send(&Request::ParallelUpload {
+            request: TransferRequest::new(
+                filename.to_string_lossy().to_string(),
+                len,
+                Vec::new(),
+                Some(meta::read(&out, self.xattrs)?),
+            ),
+            streams,
+        })?;
+
+        let id = match self.recv()? {
+            TransferResponse::Parallel { id, name, conflict } => {
+                self.report_conflict(&name, conflict);
+
+                id
+            }
+            TransferResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected response to a parallel upload",
+                ));
             }
+        };
+
+        let range_len = len.div_ceil(u64::from(streams.max(1))).max(1);
+        let ranges = (0..len)
+            .step_by(range_len as usize)
+            .map(|offset| (offset, range_len.min(len - offset)))
+            .collect::<Vec<_>>();
+
+        self.info(format_args!(
+            "uploading {} in {} ranges of up to {}",
+            bytes_to_hr(len as f64),
+            ranges.len(),
+            bytes_to_hr(range_len as f64)
+        ));
+
+        let start = Instant::now();
+        let sent = AtomicU64::new(0);
+        let mut progress = Progress::new(self.progress, len, 0);
+
+        // the whole file is hashed while the ranges are on their way
+        let hash = thread::scope(|scope| {
+            let handles = ranges
+                .iter()
+                .map(|&(offset, len)| {
+                    let connect = &connect;
+                    let sent = &sent;
+                    let limit = self.limit.clone();
+
+                    scope.spawn(move || {
+                        let mut client = connect()?;
+                        client.limit = limit;
+                        client.upload_range(id, path, offset, len, sent)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let hasher = scope.spawn(|| hash_prefix(&mut out, len));
+
+            while !handles.iter().all(|handle| handle.is_finished()) {
+                progress.set(sent.load(Ordering::Relaxed));
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            progress.set(sent.load(Ordering::Relaxed));
+            progress.finish();
+
+            for handle in handles {
+                handle.join().unwrap()?;
+            }
+
+            hasher
+                .join()
+                .unwrap()
+                .map(|hasher| hasher.finalize().to_vec())
+        })?;
+
+        self.send(&TransferDigest::new(hash.clone()))?;
+
+        let verdict = self.recv_verdict()?;
+        let complete: TransferComplete = self.recv()?;
+        accepted(verdict)?;
+
+        if !complete.verified {
+            return Err(io::Error::other(TransferError::ChecksumMismatch));
         }
 
+        self.info(format_args!(
+            "complete. bytes transfered: {} over {} streams, {}/s, sha256: {}",
+            complete.len,
+            ranges.len(),
+            bytes_to_hr(complete.len as f64 / start.elapsed().as_secs_f64()),
+            to_hex(&hash)
+        ));
+
+        Ok(())
+    }
+
+    /// Sends `len` bytes of `file` at `offset` as a range of the parallel
+    /// upload `id`, counting them in `sent`.
+    fn upload_range(
+        &mut self,
+        id: u64,
+        file: &Path,
+        offset: u64,
+        len: u64,
+        sent: &AtomicU64,
+    ) -> io::Result<()> {
+        self.send(&Request::Range(RangeRequest { id, offset, len }))?;
+
+        if let RangeResponse::Failure { reason, message } = self.recv()? {
+            return Err(io::Error::other(TransferError::Rejected {
+                reason,
+                message,
+            }));
+        }
+
+        let mut out = File::open(file)?;
+        out.seek(SeekFrom::Start(offset))?;
+
+        let mut stream = Throttled::new(&mut self.stream, self.limit.clone());
+        let mut buffer = [0u8; 8192];
         let mut bytes_sent = 0;
 
         while bytes_sent < len {
-            let read = out.read(&mut buffer)?;
-            let sent = self.write(&buffer[..read])?;
+            let chunk = buffer.len().min((len - bytes_sent) as usize);
+            let read = out.read(&mut buffer[..chunk])?;
 
-            bytes_sent += sent as u64;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            stream.write_all(&buffer[..read])?;
+
+            bytes_sent += read as u64;
+            sent.fetch_add(read as u64, Ordering::Relaxed);
         }
 
         let complete: TransferComplete = self.recv()?;
 
-        println!("complete. bytes transfered: {}", complete.len);
+        if complete.len != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("server acknowledged {} of {len} bytes", complete.len),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Uploads every regular file under `dir` that matches one of `include`
+    /// (or all of them if it's empty) and none of `exclude`. Patterns are
+    /// matched against paths relative to `dir`. Hidden files are skipped, as
+    /// the server won't accept them. Files rejected by the server are
+    /// reported and skipped, the first rejection is returned at the end.
+    pub fn transfer_tree<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        include: &[String],
+        exclude: &[String],
+    ) -> io::Result<()> {
+        if !self.supports(Capability::Tree) {
+            return Err(io::Error::other(TransferError::Rejected {
+                reason: Rejection::Incompatible,
+                message: "server does not support directory uploads".to_string(),
+            }));
+        }
+
+        let dir = dir.as_ref();
+
+        let Some(dirname) = dir
+            .canonicalize()?
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            eprintln!("invalid directory name");
+            return Ok(());
+        };
+
+        let include = build_globs(include)?;
+        let exclude = build_globs(exclude)?;
+
+        let mut files = Vec::new();
+        collect_files(dir, "", &mut files)?;
+
+        files.retain(|(_, relative, _)| {
+            (include.is_empty() || include.is_match(relative)) && !exclude.is_match(relative)
+        });
+
+        let entries = files
+            .iter()
+            .map(|(_, relative, len)| ManifestEntry {
+                path: format!("{dirname}/{relative}"),
+                len: *len,
+            })
+            .collect::<Vec<_>>();
+
+        self.info(format_args!(
+            "uploading {} files ({})",
+            entries.len(),
+            bytes_to_hr(entries.iter().map(|entry| entry.len).sum::<u64>() as f64)
+        ));
+
+        self.send(&Request::UploadTree { entries })?;
+
+        match self.recv()? {
+            ManifestResponse::Accepted => {}
+            ManifestResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+        }
+
+        let mut first_error = None;
+
+        for (path, relative, len) in files {
+            let out = File::open(&path)?;
+
+            self.info(format_args!("{relative}:"));
+
+            self.send(&TransferRequest::new(
+                format!("{dirname}/{relative}"),
+                len,
+                self.offered_codecs(),
+                Some(meta::read(&out, self.xattrs)?),
+            ))?;
+
+            match self.upload(out, len) {
+                Ok(()) => {}
+                Err(err) if is_rejection(&err) => {
+                    eprintln!("{relative}: {err}");
+                    first_error.get_or_insert(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Sends `out` from `offset` up to `len` with `sendfile`, while another
+    /// thread hashes it from the page cache. Returns false, having sent
+    /// nothing, if the file can't be sent that way.
+    fn send_zero_copy(
+        &self,
+        out: &File,
+        offset: u64,
+        len: u64,
+        hasher: &mut Sha256,
+        progress: &mut Progress,
+    ) -> io::Result<bool> {
+        let socket = &self.stream.socket;
+        let mut sent = offset;
+
+        let mut send = |sent: &mut u64| -> io::Result<()> {
+            let chunk = (len - *sent).min(zerocopy::CHUNK as u64) as usize;
+            let n = zerocopy::sendfile(socket, out, *sent, chunk)?;
+
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            if let Some(limit) = &self.limit {
+                limit.consume(n as u64);
+            }
+
+            *sent += n as u64;
+            progress.add(n as u64);
+
+            Ok(())
+        };
+
+        // the first call tells whether sendfile works for this file at all
+        if sent < len {
+            match send(&mut sent) {
+                Ok(()) => {}
+                Err(err) if zerocopy::is_unsupported(&err) => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut file = out.try_clone()?;
+
+        thread::scope(|scope| {
+            let hashing = scope.spawn(move || {
+                file.seek(SeekFrom::Start(offset))?;
+                io::copy(&mut file.take(len - offset), hasher)
+            });
+
+            while sent < len {
+                send(&mut sent)?;
+            }
+
+            hashing.join().unwrap()?;
+
+            io::Result::Ok(())
+        })?;
+
+        progress.finish();
+
+        Ok(true)
+    }
+
+    fn report_conflict(&self, name: &str, conflict: Option<ConflictPolicy>) {
+        match conflict {
+            Some(ConflictPolicy::Overwrite) => {
+                self.info(format_args!("overwriting {name} on server"))
+            }
+            Some(ConflictPolicy::Rename) => {
+                self.info(format_args!("name taken, storing as {name}"))
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the upload of `out` after its request has been sent.
+    fn upload(&mut self, mut out: File, len: u64) -> io::Result<()> {
+        let response: TransferResponse = self.recv()?;
+
+        let (offset, mut hasher, codec) = match response {
+            TransferResponse::Success {
+                name,
+                conflict,
+                codec,
+            } => {
+                self.report_conflict(&name, conflict);
+                (0, Sha256::new(), codec)
+            }
+            TransferResponse::Resume {
+                name,
+                offset,
+                hash,
+                codec,
+            } => {
+                let prefix = if offset <= len {
+                    Some(hash_prefix(&mut out, offset)?)
+                } else {
+                    None
+                };
+
+                match prefix {
+                    Some(prefix) if prefix.clone().finalize().as_slice() == hash => {
+                        self.info(format_args!(
+                            "resuming transfer of {name} at {}",
+                            bytes_to_hr(offset as f64)
+                        ));
+                        self.send(&ResumeDecision::Continue)?;
+                        (offset, prefix, codec)
+                    }
+
+                    _ => {
+                        self.info(format_args!(
+                            "partial upload on server does not match, restarting"
+                        ));
+                        self.send(&ResumeDecision::Restart)?;
+                        (0, Sha256::new(), codec)
+                    }
+                }
+            }
+            TransferResponse::Linked { name, conflict } => {
+                self.report_conflict(&name, conflict);
+                self.info(format_args!(
+                    "server already has the content, stored as {name} without sending it"
+                ));
+                return Ok(());
+            }
+            TransferResponse::Parallel { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected parallel upload response",
+                ));
+            }
+            TransferResponse::Failure { reason, message } => {
+                return Err(io::Error::other(TransferError::Rejected {
+                    reason,
+                    message,
+                }));
+            }
+        };
+
+        out.seek(SeekFrom::Start(offset))?;
+
+        let mut progress = Progress::new(self.progress, len, offset);
+
+        let sent = codec == Codec::None
+            && self.zero_copy
+            && self.stream.tls.is_none()
+            && self.send_zero_copy(&out, offset, len, &mut hasher, &mut progress)?;
+
+        let mut stream = Throttled::new(&mut self.stream, self.limit.clone());
+
+        if !sent && codec == Codec::None {
+            send_hashed(
+                &mut out,
+                len - offset,
+                &mut hasher,
+                &mut stream,
+                &mut progress,
+            )?;
+        } else if !sent {
+            let mut encoder = Encoder::new(codec, ChunkWriter::new(&mut stream))?;
+            send_hashed(
+                &mut out,
+                len - offset,
+                &mut hasher,
+                &mut encoder,
+                &mut progress,
+            )?;
+            let wire = encoder.finish()?.finish()?;
+
+            self.info(format_args!(
+                "compressed {} to {} with {codec}",
+                bytes_to_hr((len - offset) as f64),
+                bytes_to_hr(wire as f64)
+            ));
+        }
+
+        let hash = hasher.finalize().to_vec();
+        self.send(&TransferDigest::new(hash.clone()))?;
+
+        let verdict = self.recv_verdict()?;
+        let complete: TransferComplete = self.recv()?;
+        accepted(verdict)?;
+
+        if !complete.verified {
+            return Err(io::Error::other(TransferError::ChecksumMismatch));
+        }
+
+        self.info(format_args!(
+            "complete. bytes transfered: {}, sha256: {}",
+            complete.len,
+            to_hex(&hash)
+        ));
 
         Ok(())
     }
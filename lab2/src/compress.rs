@@ -0,0 +1,170 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use flate2::write::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::Codec;
+use crate::MAX_MESSAGE_LEN;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compressed data and stream uploads have no known length up front, so
+/// they are sent as chunks prefixed with their big-endian u32 length, ended
+/// by an empty chunk.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    pub wire: u64,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, wire: 0 }
+    }
+
+    /// Writes the end marker.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        Ok(self.wire + 4)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.inner.write_all(&(len as u32).to_be_bytes())?;
+        self.inner.write_all(&buf[..len])?;
+        self.wire += 4 + len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the next chunk into `buffer`; an empty chunk ends the stream.
+pub fn read_chunk<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk too long: {len} bytes"),
+        ));
+    }
+
+    buffer.resize(len, 0);
+    reader.read_exact(buffer)?;
+
+    Ok(len)
+}
+
+/// `Codec::None` data is sent as is, or in plain chunks for streams, so it
+/// has no encoder or decoder.
+fn no_codec() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no encoder or decoder for codec none",
+    )
+}
+
+pub enum Encoder<W: Write> {
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Gzip(GzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(codec: Codec, inner: W) -> io::Result<Self> {
+        match codec {
+            Codec::None => Err(no_codec()),
+            Codec::Zstd => Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(
+                inner, ZSTD_LEVEL,
+            )?)),
+            Codec::Gzip => Ok(Encoder::Gzip(GzEncoder::new(inner, Compression::default()))),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+pub enum Decoder<W: Write> {
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+    Gzip(GzDecoder<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(codec: Codec, inner: W) -> io::Result<Self> {
+        match codec {
+            Codec::None => Err(no_codec()),
+            Codec::Zstd => Ok(Decoder::Zstd(zstd::stream::write::Decoder::new(inner)?)),
+            Codec::Gzip => Ok(Decoder::Gzip(GzDecoder::new(inner))),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        match self {
+            Decoder::Zstd(decoder) => decoder.get_ref(),
+            Decoder::Gzip(decoder) => decoder.get_ref(),
+        }
+    }
+
+    /// Writes out whatever is still buffered.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+            Decoder::Gzip(decoder) => decoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Zstd(decoder) => decoder.write(buf),
+            Decoder::Gzip(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::Zstd(decoder) => decoder.flush(),
+            Decoder::Gzip(decoder) => decoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::to_hex;
+
+/// Deduplicated storage keeps one copy of each content in this directory of
+/// an upload root, named by its SHA-256, and every stored file is a hard
+/// link to one of these objects. Files with the same content therefore also
+/// share their mode, modification time and extended attributes, those of
+/// the first upload. Each user has their own store, so that knowing a hash
+/// gives no access to another user's files.
+pub const OBJECTS_DIR: &str = ".objects";
+
+fn object_path(root: &Path, hash: &[u8]) -> PathBuf {
+    let hex = to_hex(hash);
+    root.join(OBJECTS_DIR).join(&hex[..2]).join(hex)
+}
+
+/// The object in `root`'s store holding the content with `hash`, if any.
+pub fn find(root: &Path, hash: &[u8]) -> Option<PathBuf> {
+    if hash.len() != 32 {
+        return None;
+    }
+
+    Some(object_path(root, hash)).filter(|object| object.is_file())
+}
+
+/// Moves the verified upload at `temp` into place at `dest` and into the
+/// store. If the store has its content already, `dest` ends up a link to
+/// that object instead and true is returned.
+pub fn store(root: &Path, temp: &Path, hash: &[u8], dest: &Path) -> io::Result<bool> {
+    // in place before it's an object, so gc never sees it with one link
+    fs::rename(temp, dest)?;
+
+    let object = object_path(root, hash);
+
+    if let Some(parent) = object.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::hard_link(dest, &object) {
+        Ok(()) => Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => match link(&object, dest) {
+            Ok(()) => Ok(true),
+            // collected in the meantime, this copy takes its place
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                fs::hard_link(dest, &object).map(|()| false)
+            }
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// Where a link is made before being renamed over its destination.
+pub fn link_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".{name}.link"))
+}
+
+/// Makes `dest` a link to `object`, replacing whatever was there at once.
+pub fn link(object: &Path, dest: &Path) -> io::Result<()> {
+    let temp = link_path(dest);
+    let _ = fs::remove_file(&temp);
+
+    fs::hard_link(object, &temp)?;
+    fs::rename(&temp, dest)
+}
+
+/// Removes the objects no stored file links to any more from the stores of
+/// `root` and of the user directories in it. Returns how many objects were
+/// removed and their total size.
+pub fn collect_garbage(root: &Path) -> io::Result<(u64, u64)> {
+    let mut removed = (0, 0);
+
+    collect_store(root, &mut removed)?;
+
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            collect_store(&entry.path(), &mut removed)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn collect_store(root: &Path, removed: &mut (u64, u64)) -> io::Result<()> {
+    let prefixes = match fs::read_dir(root.join(OBJECTS_DIR)) {
+        Ok(prefixes) => prefixes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for prefix in prefixes {
+        for entry in fs::read_dir(prefix?.path())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if metadata.nlink() == 1 {
+                fs::remove_file(entry.path())?;
+                removed.0 += 1;
+                removed.1 += metadata.len();
+            }
+        }
+    }
+
+    Ok(())
+}
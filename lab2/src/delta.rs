@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::BlockSignature;
+use crate::DeltaOp;
+
+const MIN_BLOCK_SIZE: u64 = 2048;
+
+/// Most blocks in a signature, which keeps it well within one message.
+const MAX_BLOCKS: u64 = 1 << 18;
+
+/// Most data sent in one `DeltaOp::Literal`.
+const MAX_LITERAL: usize = 64 * 1024;
+
+const READ_AHEAD: usize = 256 * 1024;
+
+/// Block size for a file of `len` bytes: about its square root, as rsync
+/// does, so both the signature and the data around changes stay small.
+pub fn block_size(len: u64) -> u64 {
+    ((len as f64).sqrt() as u64)
+        .max(len.div_ceil(MAX_BLOCKS))
+        .max(MIN_BLOCK_SIZE)
+        .next_multiple_of(1024)
+}
+
+/// The rsync rolling checksum of a window of data, which can be moved
+/// along one byte at a time without going over the whole window again.
+pub struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    pub fn new(data: &[u8]) -> Self {
+        let mut a = 0u32;
+        let mut b = 0u32;
+
+        for (i, byte) in data.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add(((data.len() - i) as u32).wrapping_mul(*byte as u32));
+        }
+
+        Self {
+            a,
+            b,
+            len: data.len() as u32,
+        }
+    }
+
+    /// Moves the window one byte along, dropping `out` and taking in `new`.
+    pub fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Checks a block whose rolling checksum matched; half a SHA-256 is plenty
+/// to tell blocks apart.
+fn strong(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data)[..16].to_vec()
+}
+
+/// The signature of `file` in blocks of `block_size`, the last one possibly
+/// shorter.
+pub fn signature(file: &mut File, block_size: u64) -> io::Result<Vec<BlockSignature>> {
+    let mut blocks = Vec::new();
+    let mut buffer = Vec::new();
+
+    loop {
+        buffer.clear();
+        let len = (&mut *file).take(block_size).read_to_end(&mut buffer)?;
+
+        if len == 0 {
+            break;
+        }
+
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&buffer).digest(),
+            strong: strong(&buffer),
+        });
+    }
+
+    Ok(blocks)
+}
+
+/// Reads from `source` until `buffer` holds `len` bytes, hashing what it
+/// reads. Returns true at the end of `source`.
+fn fill<R: Read>(
+    source: &mut R,
+    buffer: &mut Vec<u8>,
+    len: usize,
+    hasher: &mut Sha256,
+) -> io::Result<bool> {
+    while buffer.len() < len {
+        let start = buffer.len();
+        buffer.resize(start + READ_AHEAD, 0);
+
+        let read = source.read(&mut buffer[start..]);
+        buffer.truncate(start + *read.as_ref().unwrap_or(&0));
+
+        match read {
+            Ok(0) => return Ok(true),
+            Ok(_) => hasher.update(&buffer[start..]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(false)
+}
+
+fn flush_copy<F>(copy: &mut Option<(u64, u64)>, emit: &mut F) -> io::Result<()>
+where
+    F: FnMut(DeltaOp) -> io::Result<()>,
+{
+    match copy.take() {
+        Some((block, count)) => emit(DeltaOp::Copy { block, count }),
+        None => Ok(()),
+    }
+}
+
+/// Finds the blocks of another copy's signature in new data, so that only
+/// the data around them needs sending.
+pub struct Delta {
+    block_size: usize,
+    blocks: Vec<BlockSignature>,
+    index: HashMap<u32, Vec<u64>>,
+}
+
+impl Delta {
+    pub fn new(blocks: Vec<BlockSignature>, block_size: u64) -> Self {
+        let mut index = HashMap::<u32, Vec<u64>>::new();
+
+        for (i, block) in blocks.iter().enumerate() {
+            index.entry(block.weak).or_default().push(i as u64);
+        }
+
+        Self {
+            block_size: block_size as usize,
+            blocks,
+            index,
+        }
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        let candidates = self.index.get(&weak)?;
+        let strong = strong(window);
+
+        candidates
+            .iter()
+            .copied()
+            .find(|block| self.blocks[*block as usize].strong == strong)
+    }
+
+    /// Reads `source` to its end, hashing it into `hasher`, and passes
+    /// `emit` the ops that rebuild it from the other copy. Runs of blocks
+    /// are sent as one `Copy`.
+    pub fn diff<R, F>(&self, source: &mut R, hasher: &mut Sha256, mut emit: F) -> io::Result<()>
+    where
+        R: Read,
+        F: FnMut(DeltaOp) -> io::Result<()>,
+    {
+        let size = self.block_size;
+        let mut buffer = Vec::new();
+        let mut eof = false;
+        // the window at `start` is being matched, the data from `literal`
+        // up to it matched nothing
+        let mut start = 0;
+        let mut literal = 0;
+        let mut copy = None;
+        let mut rolling: Option<Rolling> = None;
+
+        loop {
+            // keep the window and the byte after it in the buffer
+            if !eof && buffer.len() <= start + size {
+                buffer.drain(..literal);
+                start -= literal;
+                literal = 0;
+                eof = fill(source, &mut buffer, start + size + 1, hasher)?;
+            }
+
+            let end = (start + size).min(buffer.len());
+            let window = &buffer[start..end];
+
+            if window.is_empty() {
+                break;
+            }
+
+            let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+
+            if let Some(block) = self.find(weak, window) {
+                if literal < start {
+                    flush_copy(&mut copy, &mut emit)?;
+                    emit(DeltaOp::Literal(buffer[literal..start].to_vec()))?;
+                }
+
+                copy = match copy {
+                    Some((first, count)) if first + count == block => Some((first, count + 1)),
+                    _ => {
+                        flush_copy(&mut copy, &mut emit)?;
+                        Some((block, 1))
+                    }
+                };
+
+                start = end;
+                literal = end;
+                rolling = None;
+                continue;
+            }
+
+            if start - literal >= MAX_LITERAL {
+                flush_copy(&mut copy, &mut emit)?;
+                emit(DeltaOp::Literal(buffer[literal..start].to_vec()))?;
+                literal = start;
+            }
+
+            // a window cut short by the end of the data is only matched whole
+            let Some(&next) = buffer.get(end).filter(|_| end - start == size) else {
+                break;
+            };
+
+            if let Some(rolling) = &mut rolling {
+                rolling.roll(buffer[start], next);
+            }
+
+            start += 1;
+        }
+
+        flush_copy(&mut copy, &mut emit)?;
+
+        // up to a window more than the most in one literal
+        for data in buffer[literal..].chunks(MAX_LITERAL) {
+            emit(DeltaOp::Literal(data.to_vec()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIZE: usize = 1024;
+
+    /// Deterministic data that doesn't repeat within a block.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn delta(basis: &[u8]) -> Delta {
+        let blocks = basis
+            .chunks(SIZE)
+            .map(|block| BlockSignature {
+                weak: Rolling::new(block).digest(),
+                strong: strong(block),
+            })
+            .collect();
+
+        Delta::new(blocks, SIZE as u64)
+    }
+
+    /// Diffs `new` against `basis` and rebuilds it from the ops, as the
+    /// server does.
+    fn round_trip(basis: &[u8], new: &[u8]) -> (Vec<u8>, Vec<DeltaOp>) {
+        let mut hasher = Sha256::new();
+        let mut ops = Vec::new();
+
+        delta(basis)
+            .diff(&mut &new[..], &mut hasher, |op| {
+                ops.push(op);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(hasher.finalize()[..], Sha256::digest(new)[..]);
+
+        let mut rebuilt = Vec::new();
+
+        for op in &ops {
+            match op {
+                DeltaOp::Copy { block, count } => {
+                    let start = *block as usize * SIZE;
+                    let end = ((block + count) as usize * SIZE).min(basis.len());
+                    rebuilt.extend_from_slice(&basis[start..end]);
+                }
+                DeltaOp::Literal(data) => {
+                    assert!(!data.is_empty() && data.len() <= MAX_LITERAL);
+                    rebuilt.extend_from_slice(data);
+                }
+                DeltaOp::End => panic!("diff doesn't end the delta"),
+            }
+        }
+
+        (rebuilt, ops)
+    }
+
+    fn literal_len(ops: &[DeltaOp]) -> usize {
+        ops.iter()
+            .map(|op| match op {
+                DeltaOp::Literal(data) => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn rolling_matches_a_recompute() {
+        let data = data(4096, 1);
+
+        for len in [1, 7, SIZE] {
+            let mut rolling = Rolling::new(&data[..len]);
+
+            for start in 1..data.len() - len {
+                rolling.roll(data[start - 1], data[start + len - 1]);
+                let window = &data[start..start + len];
+                assert_eq!(
+                    rolling.digest(),
+                    Rolling::new(window).digest(),
+                    "{len} at {start}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unchanged_data_is_one_copy() {
+        let basis = data(10 * SIZE + 100, 2);
+        let (rebuilt, ops) = round_trip(&basis, &basis);
+
+        assert_eq!(rebuilt, basis);
+        assert!(matches!(
+            ops[..],
+            [DeltaOp::Copy {
+                block: 0,
+                count: 11
+            }]
+        ));
+    }
+
+    #[test]
+    fn edits_round_trip() {
+        let basis = data(20 * SIZE + 300, 3);
+
+        let mut changed = basis.clone();
+        changed[5 * SIZE + 10] ^= 0xff;
+
+        let mut inserted = basis.clone();
+        inserted.splice(3 * SIZE + 5..3 * SIZE + 5, data(777, 4));
+
+        let mut removed = basis.clone();
+        removed.drain(7 * SIZE..9 * SIZE + 13);
+
+        let mut appended = basis.clone();
+        appended.extend(data(3000, 5));
+
+        for new in [
+            changed,
+            inserted,
+            removed,
+            appended,
+            basis[SIZE / 2..].to_vec(),
+        ] {
+            let (rebuilt, ops) = round_trip(&basis, &new);
+
+            assert_eq!(rebuilt, new);
+            assert!(literal_len(&ops) < 4 * SIZE, "{}", literal_len(&ops));
+        }
+    }
+
+    #[test]
+    fn unrelated_and_empty_data_round_trip() {
+        let basis = data(8 * SIZE, 6);
+
+        for new in [Vec::new(), data(10, 7), data(MAX_LITERAL * 3 + 5, 8)] {
+            let (rebuilt, ops) = round_trip(&basis, &new);
+
+            assert_eq!(rebuilt, new);
+            assert_eq!(literal_len(&ops), new.len());
+        }
+
+        let (rebuilt, ops) = round_trip(&[], &basis);
+        assert_eq!(rebuilt, basis);
+        assert_eq!(literal_len(&ops), basis.len());
+    }
+}
@@ -0,0 +1,235 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::to_hex;
+
+/// Output of a hook kept for the log, the rest is read and dropped.
+const MAX_OUTPUT: u64 = 4096;
+
+/// How often a running hook is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Commands run by the server as uploads come in, through `sh -c`, with the
+/// upload described in their environment, see `Upload`.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    /// Run on each verified upload before it is moved into place. If it
+    /// fails or times out, the upload goes to quarantine instead and the
+    /// client is told why, the first line the hook printed if any.
+    pub validate: Option<String>,
+    /// Run one after the other once an upload is in place, without holding
+    /// up the client.
+    pub post_upload: Vec<String>,
+    /// Named pipe told about each upload in place, see `notify`.
+    pub notify_pipe: Option<PathBuf>,
+    /// Hooks still running after this long are killed, along with anything
+    /// they started.
+    pub timeout: Duration,
+}
+
+/// An upload as passed to hooks, in `LAB2_PATH`, `LAB2_NAME`, `LAB2_SIZE`,
+/// `LAB2_SHA256`, `LAB2_CLIENT` and, with accounts, `LAB2_USER`.
+pub struct Upload {
+    /// The temp file when validating, the stored file afterwards.
+    pub path: PathBuf,
+    /// Path below the user's upload root.
+    pub name: String,
+    pub len: u64,
+    pub hash: Vec<u8>,
+    pub client: String,
+    pub user: Option<String>,
+}
+
+impl Upload {
+    fn env(&self) -> Vec<(&'static str, OsString)> {
+        let mut env = vec![
+            ("LAB2_PATH", self.path.clone().into_os_string()),
+            ("LAB2_NAME", self.name.clone().into()),
+            ("LAB2_SIZE", self.len.to_string().into()),
+            ("LAB2_SHA256", to_hex(&self.hash).into()),
+            ("LAB2_CLIENT", self.client.clone().into()),
+        ];
+
+        if let Some(user) = &self.user {
+            env.push(("LAB2_USER", user.clone().into()));
+        }
+
+        env
+    }
+}
+
+/// How a hook ended: its exit status, none if it was killed for running
+/// over the timeout, and what it printed on stdout and stderr.
+pub struct Outcome {
+    pub status: Option<ExitStatus>,
+    pub output: String,
+    pub elapsed: Duration,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        self.status.is_some_and(|status| status.success())
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{status} after {:.2}s", self.elapsed.as_secs_f64()),
+            None => write!(f, "killed after {:.2}s", self.elapsed.as_secs_f64()),
+        }
+    }
+}
+
+/// Runs `command` for `upload`, killing it after `timeout`.
+pub fn run(command: &str, upload: &Upload, timeout: Duration) -> io::Result<Outcome> {
+    let (mut reader, writer) = io::pipe()?;
+    let start = Instant::now();
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(upload.env())
+        .stdin(Stdio::null())
+        .stdout(writer.try_clone()?)
+        .stderr(writer)
+        // a group of its own, so a timeout gets whatever it started too
+        .process_group(0)
+        .spawn()?;
+
+    let (sender, output) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = (&mut reader).take(MAX_OUTPUT).read_to_end(&mut output);
+        // so the hook never blocks on a full pipe
+        let _ = io::copy(&mut reader, &mut io::sink());
+        let _ = sender.send(output);
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+
+        if start.elapsed() >= timeout {
+            // SAFETY: the child hasn't been reaped, try_wait just said so, so
+            // its pid still names the process group it leads.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            child.wait()?;
+            break None;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let elapsed = start.elapsed();
+
+    // something left running in the background may hold the pipe open
+    let output = output
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap_or_default();
+
+    Ok(Outcome {
+        status,
+        output: String::from_utf8_lossy(&output).trim_end().to_string(),
+        elapsed,
+    })
+}
+
+/// Writes a line about `upload` to the named pipe at `path`: name, size,
+/// SHA-256, client address, user or `-`, and path, separated by tabs. Each
+/// line goes out in one write, so lines from several uploads don't mix.
+/// Fails with `ENXIO` rather than waiting if no one has the pipe open for
+/// reading, and with `WouldBlock` if the reader has fallen behind.
+pub fn notify(path: &Path, upload: &Upload) -> io::Result<()> {
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        upload.name,
+        upload.len,
+        to_hex(&upload.hash),
+        upload.client,
+        upload.user.as_deref().unwrap_or("-"),
+        upload.path.display()
+    );
+
+    let mut pipe = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+
+    pipe.write_all(line.as_bytes())
+}
+
+fn log(name: &str, hook: &str, command: &str, outcome: &io::Result<Outcome>) {
+    match outcome {
+        Ok(outcome) => {
+            println!("{name}: {hook} hook `{command}` {outcome}");
+
+            for line in outcome.output.lines() {
+                println!("{name}: | {line}");
+            }
+        }
+        Err(err) => println!("{name}: error running {hook} hook `{command}`: {err}"),
+    }
+}
+
+impl Hooks {
+    /// Runs the validation hook, if any, on `upload`. Returns why it turned
+    /// the upload down if it did.
+    pub fn validate(&self, upload: &Upload) -> Result<(), String> {
+        let Some(command) = &self.validate else {
+            return Ok(());
+        };
+
+        let outcome = run(command, upload, self.timeout);
+        log(&upload.name, "validation", command, &outcome);
+
+        match outcome {
+            Ok(outcome) if outcome.success() => Ok(()),
+            Ok(outcome) => Err(match outcome.output.lines().find(|line| !line.is_empty()) {
+                Some(line) => line.trim().to_string(),
+                None => format!("validation hook {outcome}"),
+            }),
+            Err(err) => Err(format!("can't run validation hook: {err}")),
+        }
+    }
+
+    /// Tells the pipe and runs the post-upload hooks about `upload`, which
+    /// is in place, on a thread of its own.
+    pub fn uploaded(&self, upload: Upload) {
+        if self.post_upload.is_empty() && self.notify_pipe.is_none() {
+            return;
+        }
+
+        let hooks = self.clone();
+
+        thread::spawn(move || {
+            if let Some(pipe) = &hooks.notify_pipe {
+                if let Err(err) = notify(pipe, &upload) {
+                    println!("{}: can't notify {}: {err}", upload.name, pipe.display());
+                }
+            }
+
+            for command in &hooks.post_upload {
+                let outcome = run(command, &upload, hooks.timeout);
+                log(&upload.name, "post-upload", command, &outcome);
+            }
+        });
+    }
+}
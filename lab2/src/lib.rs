@@ -1,28 +1,334 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_binary::binary_stream::Endian;
+use sha2::Digest;
+use sha2::Sha256;
 use socket2::SockAddr;
 
+pub(crate) const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Version of the messages below. Bumped whenever they change in a way old
+/// binaries can't read; the server accepts clients speaking
+/// `MIN_PROTOCOL_VERSION` or later.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Starts every `Hello`, so that anything else connecting is turned away
+/// before its bytes are read as a request.
+pub const HELLO_MAGIC: [u8; 4] = *b"LAB2";
+
+/// First message on every connection, after the TLS handshake if any.
+/// `capabilities` are `Capability` names; names a side doesn't know are
+/// ignored, so features can be added without a version bump.
+#[derive(Serialize, Deserialize)]
+pub struct Hello {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        Self {
+            magic: HELLO_MAGIC,
+            version: PROTOCOL_VERSION,
+            capabilities: Capability::names(capabilities),
+        }
+    }
+}
+
+/// `version` is the one both sides speak from here on; `capabilities` are
+/// the ones both support.
+#[derive(Serialize, Deserialize)]
+pub enum HelloResponse {
+    Accepted {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    Failure {
+        reason: Rejection,
+        message: String,
+    },
+}
+
+/// Optional protocol features. Only those both sides list in the hello are
+/// used on a connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Capability {
+    /// Compressed uploads, see `Codec`.
+    Compression,
+    /// Continuing interrupted uploads, see `TransferResponse::Resume`.
+    Resume,
+    /// SHA-256 hashes of stored files in listings and downloads.
+    Checksums,
+    /// Uploads split over several connections, see `Request::ParallelUpload`.
+    Parallel,
+    /// Directory uploads, see `Request::UploadTree`.
+    Tree,
+    /// Uploads of unknown length, see `Request::UploadStream`.
+    Stream,
+    /// Delta uploads, see `Request::UploadDelta`.
+    Delta,
+    /// Skipping data the server holds already, see `Request::UploadHashed`.
+    Dedup,
+    /// Uploads checked by the server before they are stored, see `Verdict`.
+    Validation,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 9] = [
+        Capability::Compression,
+        Capability::Resume,
+        Capability::Checksums,
+        Capability::Parallel,
+        Capability::Tree,
+        Capability::Stream,
+        Capability::Delta,
+        Capability::Dedup,
+        Capability::Validation,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Compression => "compression",
+            Capability::Resume => "resume",
+            Capability::Checksums => "checksums",
+            Capability::Parallel => "parallel",
+            Capability::Tree => "tree",
+            Capability::Stream => "stream",
+            Capability::Delta => "delta",
+            Capability::Dedup => "dedup",
+            Capability::Validation => "validation",
+        }
+    }
+
+    pub fn names(capabilities: &[Capability]) -> Vec<String> {
+        capabilities.iter().map(|c| c.name().to_string()).collect()
+    }
+
+    /// The known capabilities among `names`.
+    pub fn parse(names: &[String]) -> Vec<Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(|c| names.iter().any(|name| name == c.name()))
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TransferComplete {
     pub len: u64,
+    pub verified: bool,
 }
 
 impl TransferComplete {
-    pub fn new(len: u64) -> Self {
-        Self { len }
+    pub fn new(len: u64, verified: bool) -> Self {
+        Self { len, verified }
+    }
+}
+
+/// On connections with `Capability::Validation`, the server answers every
+/// digest with this first and the usual `TransferComplete`, `DeltaComplete`
+/// or `StreamComplete` after. A rejected upload is not stored and reported
+/// as not verified in the latter; a checksum mismatch isn't a rejection.
+#[derive(Serialize, Deserialize)]
+pub enum Verdict {
+    Accepted,
+    Rejected { reason: Rejection, message: String },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferDigest {
+    pub hash: Vec<u8>,
+}
+
+impl TransferDigest {
+    pub fn new(hash: Vec<u8>) -> Self {
+        Self { hash }
     }
 }
 
+/// First message on every connection; each connection carries one request,
+/// optionally preceded by `Authenticate`.
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    Authenticate(Credentials),
+    Upload(TransferRequest),
+    UploadTree {
+        entries: Vec<ManifestEntry>,
+    },
+    List,
+    Download {
+        name: String,
+    },
+    /// Starts an upload whose data is sent as `Range`s over `streams`
+    /// further connections; answered with `TransferResponse::Parallel`.
+    /// The digest follows on this connection once every range is done.
+    ParallelUpload {
+        request: TransferRequest,
+        streams: u32,
+    },
+    Range(RangeRequest),
+    UploadStream(StreamRequest),
+    /// Uploads a new version of a file the server may have a copy of. It is
+    /// answered like an `Upload`, though never with `Resume` or compression,
+    /// then with a `DeltaSignature` of that copy. The client sends
+    /// `DeltaOp`s, the digest, and gets a `DeltaComplete`.
+    UploadDelta(TransferRequest),
+    /// An `Upload` announcing the SHA-256 of the file up front. If the
+    /// server stores that content already it answers with
+    /// `TransferResponse::Linked` and no data is sent; otherwise it goes on
+    /// as an `Upload`.
+    UploadHashed {
+        request: TransferRequest,
+        hash: Vec<u8>,
+    },
+}
+
+/// Checksums of one block of a file, see `delta`.
+#[derive(Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+/// The blocks of the server's copy of a file, none if it has no copy.
+#[derive(Serialize, Deserialize)]
+pub struct DeltaSignature {
+    pub block_size: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// One step of rebuilding a delta upload: `count` blocks of the server's
+/// copy starting at `block`, or data the copy doesn't have.
+#[derive(Serialize, Deserialize)]
+pub enum DeltaOp {
+    Copy { block: u64, count: u64 },
+    Literal(Vec<u8>),
+    End,
+}
+
+/// The outcome of a delta upload, `reused` bytes of which came from the
+/// server's copy instead of over the wire.
+#[derive(Serialize, Deserialize)]
+pub struct DeltaComplete {
+    pub len: u64,
+    pub verified: bool,
+    pub reused: u64,
+}
+
+/// An upload of unknown length, e.g. from a pipe. It is answered like an
+/// `Upload`, though never with `Resume`; the data then follows as chunks,
+/// see `compress::ChunkWriter`, compressed with the codec picked if any.
+/// After the digest the server answers with a `StreamComplete`.
+#[derive(Serialize, Deserialize)]
+pub struct StreamRequest {
+    pub name: String,
+    pub codecs: Vec<Codec>,
+    pub metadata: Option<FileMetadata>,
+}
+
+/// The outcome of a stream upload. The server may send a `Failure` while
+/// the data is still arriving, if the upload breaks a limit; it then drops
+/// whatever else the client sends.
+#[derive(Serialize, Deserialize)]
+pub enum StreamComplete {
+    Success(TransferComplete),
+    Failure { reason: Rejection, message: String },
+}
+
+/// `len` bytes of the parallel upload `id` starting at `offset`; the data
+/// follows once the server accepts, and the server acknowledges it with a
+/// `TransferComplete`.
+#[derive(Serialize, Deserialize)]
+pub struct RangeRequest {
+    pub id: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum RangeResponse {
+    Accepted,
+    Failure { reason: Rejection, message: String },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum AuthResponse {
+    Success { user: String },
+    Failure { reason: Rejection, message: String },
+}
+
+/// `path` is relative to the upload root and `/`-separated.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ManifestResponse {
+    Accepted,
+    Failure { reason: Rejection, message: String },
+}
+
+/// `modified` is in seconds since the Unix epoch; `hash` is the SHA-256
+/// recorded when the file was uploaded, if the server has one.
+#[derive(Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub len: u64,
+    pub modified: u64,
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ListResponse {
+    Success { files: Vec<FileEntry> },
+    Failure { reason: Rejection, message: String },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum DownloadResponse {
+    Success { len: u64, hash: Option<Vec<u8>> },
+    Failure { reason: Rejection, message: String },
+}
+
+/// `codecs` are the compression codecs the client can send the file with,
+/// most preferred first. `metadata` is applied to the file once it's
+/// stored, as far as the server's policy allows.
 #[derive(Serialize, Deserialize)]
 pub struct TransferRequest {
     pub len: u64,
     pub name: String,
+    pub codecs: Vec<Codec>,
+    pub metadata: Option<FileMetadata>,
 }
 
 impl TransferRequest {
-    pub fn new(name: String, len: u64) -> Self {
-        Self { name, len }
+    pub fn new(name: String, len: u64, codecs: Vec<Codec>, metadata: Option<FileMetadata>) -> Self {
+        Self {
+            name,
+            len,
+            codecs,
+            metadata,
+        }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -34,10 +340,130 @@ impl TransferRequest {
     }
 }
 
+/// Unix mode bits, modification time in nanoseconds since the epoch and
+/// extended attributes of an uploaded file, see `meta`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub modified: u64,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    Reject,
+    Overwrite,
+    Rename,
+}
+
+/// Compression applied to file data on the wire. Compressed data is sent in
+/// length-prefixed chunks, see `compress`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Codec {
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let codec = match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        };
+
+        f.write_str(codec)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Rejection {
+    FileTooLarge,
+    NameInvalid,
+    AlreadyExists,
+    DiskFull,
+    QuotaExceeded,
+    Unauthorized,
+    ServerBusy,
+    NotFound,
+    InvalidRange,
+    Incompatible,
+    Internal,
+    ValidationFailed,
+}
+
+impl Rejection {
+    /// Exit status of the client binary when the server rejects an upload.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Rejection::FileTooLarge => 10,
+            Rejection::NameInvalid => 11,
+            Rejection::AlreadyExists => 12,
+            Rejection::DiskFull => 13,
+            Rejection::QuotaExceeded => 14,
+            Rejection::Unauthorized => 15,
+            Rejection::ServerBusy => 16,
+            Rejection::Internal => 17,
+            Rejection::NotFound => 18,
+            Rejection::InvalidRange => 19,
+            Rejection::Incompatible => 21,
+            Rejection::ValidationFailed => 22,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Rejection::FileTooLarge => "file too large",
+            Rejection::NameInvalid => "invalid file name",
+            Rejection::AlreadyExists => "file already exists",
+            Rejection::DiskFull => "server disk full",
+            Rejection::QuotaExceeded => "quota exceeded",
+            Rejection::Unauthorized => "unauthorized",
+            Rejection::ServerBusy => "server busy",
+            Rejection::NotFound => "file not found",
+            Rejection::InvalidRange => "invalid range",
+            Rejection::Incompatible => "incompatible protocol",
+            Rejection::Internal => "internal server error",
+            Rejection::ValidationFailed => "rejected by validation",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// `name` is what the server stores the file as; `conflict` is set when a
+/// file with the requested name already existed and the policy was applied.
+/// `codec` is the one picked from the request's codecs for the file data.
 #[derive(Serialize, Deserialize)]
 pub enum TransferResponse {
-    Success,
-    Failure,
+    Success {
+        name: String,
+        conflict: Option<ConflictPolicy>,
+        codec: Codec,
+    },
+    Resume {
+        name: String,
+        offset: u64,
+        hash: Vec<u8>,
+        codec: Codec,
+    },
+    Parallel {
+        id: u64,
+        name: String,
+        conflict: Option<ConflictPolicy>,
+    },
+    Failure {
+        reason: Rejection,
+        message: String,
+    },
+    /// The file was stored from content the server already had.
+    Linked {
+        name: String,
+        conflict: Option<ConflictPolicy>,
+    },
 }
 
 impl TransferResponse {
@@ -50,16 +476,38 @@ impl TransferResponse {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub enum ResumeDecision {
+    Continue,
+    Restart,
+}
+
+pub mod auth;
 pub mod client;
+pub mod compress;
+pub mod dedup;
+pub mod delta;
+pub mod hook;
+pub mod limit;
+pub mod meta;
+pub mod progress;
+pub mod quota;
 pub mod server;
+pub mod tls;
+pub mod udp;
+pub mod zerocopy;
 
 pub fn format_sockaddr(addr: &SockAddr) -> String {
     if let Some(ipv4) = addr.as_socket_ipv4() {
         format!("{ipv4}")
     } else if let Some(ipv6) = addr.as_socket_ipv6() {
-        format!("{ipv6}")
+        // IPv4 clients of a dual-stack server show up as ::ffff:a.b.c.d
+        match ipv6.ip().to_ipv4_mapped() {
+            Some(ipv4) => format!("{ipv4}:{}", ipv6.port()),
+            None => format!("{ipv6}"),
+        }
     } else {
-        format!("UNKNOWN")
+        "UNKNOWN".to_string()
     }
 }
 
@@ -74,3 +522,184 @@ pub fn bytes_to_hr(bytes: f64) -> String {
         format!("{:.2}GiB", bytes / (1024.0 * 1024.0 * 1024.0))
     }
 }
+
+/// Parses sizes and rates the way `bytes_to_hr` prints them, e.g. `512`,
+/// `1.5GiB` or `10MiB/s`.
+pub fn hr_to_bytes(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let text = text.strip_suffix("/s").unwrap_or(text);
+
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid number in {text:?}"))?;
+
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.,
+        "kib" => 1024.,
+        "mib" => 1024. * 1024.,
+        "gib" => 1024. * 1024. * 1024.,
+        unit => {
+            return Err(format!(
+                "unknown unit {unit:?}, expected B, KiB, MiB or GiB"
+            ))
+        }
+    };
+
+    let bytes = number * scale;
+
+    // casting would quietly saturate
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("{text:?} is too large"));
+    }
+
+    let bytes = bytes as u64;
+
+    if bytes == 0 {
+        return Err("must be at least 1 byte".to_string());
+    }
+
+    Ok(bytes)
+}
+
+/// Parses a duration in seconds, optionally suffixed with `ms`, `s`, `m` or
+/// `h`, e.g. `30`, `250ms`, `90s` or `2m`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+
+    let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 1)
+    } else {
+        match text.char_indices().last() {
+            Some((i, 's')) => (&text[..i], 1000),
+            Some((i, 'm')) => (&text[..i], 60 * 1000),
+            Some((i, 'h')) => (&text[..i], 60 * 60 * 1000),
+            _ => (text, 1000),
+        }
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(scale))
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("invalid duration {text:?}, expected e.g. 30s or 2m"))
+}
+
+/// Whether `err` comes from a socket read or write timing out. Depending on
+/// the platform that is `WouldBlock` or `TimedOut`.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// SHA-256 state after hashing the first `len` bytes of `file`, so the
+/// caller can keep feeding it the rest of the upload.
+pub fn hash_prefix(file: &mut File, len: u64) -> io::Result<Sha256> {
+    let mut hasher = Sha256::new();
+
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut file.take(len), &mut hasher)?;
+
+    Ok(hasher)
+}
+
+/// Messages are framed with a big-endian u32 length so that a message
+/// followed directly by file data can't be read as one chunk.
+pub(crate) fn write_message<W: Write, T: Serialize>(writer: &mut W, value: &T) -> io::Result<()> {
+    let bytes = serde_binary::to_vec(value, Endian::Big).unwrap();
+
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(&bytes)
+}
+
+pub(crate) fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message too long: {len} bytes"),
+        ));
+    }
+
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer)?;
+
+    serde_binary::from_slice(&buffer, Endian::Big)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hr_to_bytes_units() {
+        assert_eq!(hr_to_bytes("512"), Ok(512));
+        assert_eq!(hr_to_bytes("512B"), Ok(512));
+        assert_eq!(hr_to_bytes("2KiB"), Ok(2048));
+        assert_eq!(hr_to_bytes("1.5MiB"), Ok(3 << 19));
+        assert_eq!(hr_to_bytes("10 mib/s"), Ok(10 << 20));
+        assert_eq!(hr_to_bytes(" 1GiB "), Ok(1 << 30));
+    }
+
+    #[test]
+    fn hr_to_bytes_rejects_garbage() {
+        for text in [
+            "", "MiB", "-1", "1.2.3MiB", "1e3", "10MB", "ten", "0", "0.1",
+        ] {
+            assert!(hr_to_bytes(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn hr_to_bytes_rejects_overflow() {
+        assert!(hr_to_bytes("17179869184GiB").is_err());
+        assert!(hr_to_bytes("99999999999999999999").is_err());
+        assert_eq!(hr_to_bytes("17179869183GiB"), Ok(17179869183 << 30));
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration(" 1 h "), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        for text in ["", "s", "ms", "-1s", "1.5s", "10d", "1 2s", "s10", "ten"] {
+            assert!(parse_duration(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        let max = u64::MAX / (60 * 60 * 1000);
+        assert_eq!(
+            parse_duration(&format!("{max}h")),
+            Ok(Duration::from_millis(max * 3600 * 1000))
+        );
+        assert!(parse_duration(&format!("{}h", max + 1)).is_err());
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_err());
+        assert!(parse_duration("99999999999999999999ms").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Token bucket allowing `rate` bytes per second, with bursts of up to a
+/// tenth of that. It can be shared between connections; each caller sleeps
+/// off the debt its own bytes put the bucket in.
+pub struct RateLimit {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate / 10.,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens, sleeping until the bucket is out of debt.
+    pub fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.last).as_secs_f64() * self.rate)
+                .min(self.rate / 10.);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0. {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Writer that takes every buffer it passes on out of `limit`.
+pub struct Throttled<W: Write> {
+    inner: W,
+    limit: Option<Arc<RateLimit>>,
+}
+
+impl<W: Write> Throttled<W> {
+    pub fn new(inner: W, limit: Option<Arc<RateLimit>>) -> Self {
+        Self { inner, limit }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        if let Some(limit) = &self.limit {
+            limit.consume(written as u64);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Upload rates are checked over windows this long, so short hiccups don't
+/// count as stalls.
+pub const MIN_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Floor on how fast an upload must arrive, failing it once a whole
+/// `MIN_RATE_WINDOW` brought less than `rate` bytes per second.
+pub struct MinRate {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl MinRate {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Starts a new window, e.g. when the next file's data begins.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.bytes = 0;
+    }
+
+    pub fn record(&mut self, bytes: u64) -> io::Result<()> {
+        self.bytes += bytes;
+
+        let elapsed = self.start.elapsed();
+
+        if elapsed < MIN_RATE_WINDOW {
+            return Ok(());
+        }
+
+        let rate = self.bytes as f64 / elapsed.as_secs_f64();
+
+        if rate < self.rate as f64 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "upload stalled at {}/s, below the minimum of {}/s",
+                    crate::bytes_to_hr(rate),
+                    crate::bytes_to_hr(self.rate as f64)
+                ),
+            ));
+        }
+
+        self.reset();
+
+        Ok(())
+    }
+}
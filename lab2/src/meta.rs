@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+use crate::FileMetadata;
+
+/// Only extended attributes in this namespace are sent and applied; the
+/// others are for the system and need privileges to set.
+pub const XATTR_NAMESPACE: &str = "user.";
+
+/// Mode bits clients are allowed to set by default: no setuid, setgid or
+/// sticky bit.
+pub const DEFAULT_MODE_MASK: u32 = 0o777;
+
+/// Reads the metadata of `file` to send along with it, its `user.` extended
+/// attributes included if `xattrs` is set.
+pub fn read(file: &File, xattrs: bool) -> io::Result<FileMetadata> {
+    let metadata = file.metadata()?;
+
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+
+    let xattrs = if xattrs {
+        match list_xattrs(file) {
+            Ok(xattrs) => xattrs,
+            Err(err) if crate::zerocopy::is_unsupported(&err) => Vec::new(),
+            Err(err) => return Err(err),
+        }
+    } else {
+        Vec::new()
+    };
+
+    Ok(FileMetadata {
+        mode: metadata.permissions().mode() & 0o7777,
+        modified,
+        xattrs,
+    })
+}
+
+/// Applies `metadata` to a received file, keeping only the mode bits in
+/// `mode_mask` and, unless `xattrs` is set, no extended attributes. The
+/// modification time is set last, as writing the attributes would bump it.
+pub fn apply(file: &File, metadata: &FileMetadata, mode_mask: u32, xattrs: bool) -> io::Result<()> {
+    if xattrs {
+        for (name, value) in &metadata.xattrs {
+            if name.starts_with(XATTR_NAMESPACE) {
+                set_xattr(file, name, value)?;
+            }
+        }
+    }
+
+    file.set_permissions(Permissions::from_mode(metadata.mode & mode_mask))?;
+    file.set_modified(UNIX_EPOCH + Duration::from_nanos(metadata.modified))
+}
+
+#[cfg(target_os = "linux")]
+fn list_xattrs(file: &File) -> io::Result<Vec<(String, Vec<u8>)>> {
+    use std::ffi::CString;
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    let fd = file.as_raw_fd();
+
+    // SAFETY: a null buffer of size 0 only asks for the size needed.
+    let size = unsafe { libc::flistxattr(fd, ptr::null_mut(), 0) };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut names = vec![0u8; size as usize];
+
+    // SAFETY: `names` is valid for writes of its length.
+    let size = unsafe { libc::flistxattr(fd, names.as_mut_ptr().cast(), names.len()) };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut xattrs = Vec::new();
+
+    for name in names[..size as usize].split(|byte| *byte == 0) {
+        let Ok(name) = std::str::from_utf8(name) else {
+            continue;
+        };
+
+        if !name.starts_with(XATTR_NAMESPACE) {
+            continue;
+        }
+
+        let cname = CString::new(name).map_err(io::Error::other)?;
+
+        // SAFETY: as above, only asks for the size of the value.
+        let len = unsafe { libc::fgetxattr(fd, cname.as_ptr(), ptr::null_mut(), 0) };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut value = vec![0u8; len as usize];
+
+        // SAFETY: `value` is valid for writes of its length.
+        let len =
+            unsafe { libc::fgetxattr(fd, cname.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        value.truncate(len as usize);
+        xattrs.push((name.to_string(), value));
+    }
+
+    Ok(xattrs)
+}
+
+#[cfg(target_os = "linux")]
+fn set_xattr(file: &File, name: &str, value: &[u8]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::fd::AsRawFd;
+
+    let name = CString::new(name).map_err(io::Error::other)?;
+
+    // SAFETY: `name` is NUL-terminated and `value` valid for its length.
+    let result = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list_xattrs(_file: &File) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattr(_file: &File, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,182 @@
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::bytes_to_hr;
+
+const BAR_WIDTH: usize = 20;
+
+/// How transfer progress is shown. `Bar` redraws a single line and needs a
+/// terminal, `Log` prints a line every few seconds like the server does.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgressMode {
+    Bar,
+    Log,
+    Quiet,
+}
+
+impl ProgressMode {
+    /// `Bar` if stdout is a terminal, `Log` otherwise.
+    pub fn detect() -> Self {
+        if io::stdout().is_terminal() {
+            ProgressMode::Bar
+        } else {
+            ProgressMode::Log
+        }
+    }
+
+    fn interval(self) -> Duration {
+        match self {
+            ProgressMode::Bar => Duration::from_millis(500),
+            _ => Duration::from_secs(3),
+        }
+    }
+}
+
+fn format_eta(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// Progress of one transfer of `len` bytes, `start` of which were already
+/// there (a resumed upload). Without a `len`, only what's done so far and
+/// the rates are shown.
+pub struct Progress {
+    mode: ProgressMode,
+    len: Option<u64>,
+    done: u64,
+    initial: u64,
+    start: Instant,
+    timer: Instant,
+    done_at_timer: u64,
+    current: f64,
+    drawn: bool,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode, len: u64, start: u64) -> Self {
+        Self {
+            mode,
+            len: Some(len),
+            done: start,
+            initial: start,
+            start: Instant::now(),
+            timer: Instant::now(),
+            done_at_timer: start,
+            current: 0.,
+            drawn: false,
+        }
+    }
+
+    /// Progress of a transfer whose length isn't known.
+    pub fn unbounded(mode: ProgressMode) -> Self {
+        Self {
+            len: None,
+            ..Self::new(mode, 0, 0)
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.set(self.done + bytes);
+    }
+
+    pub fn set(&mut self, done: u64) {
+        self.done = done;
+
+        if self.timer.elapsed() >= self.mode.interval() {
+            self.current =
+                (self.done - self.done_at_timer) as f64 / self.timer.elapsed().as_secs_f64();
+            self.timer = Instant::now();
+            self.done_at_timer = self.done;
+
+            self.draw();
+        }
+    }
+
+    /// Draws the final state and ends the bar's line.
+    pub fn finish(&mut self) {
+        if self.mode == ProgressMode::Bar && self.drawn {
+            self.current = self.average();
+            self.draw();
+            println!();
+        }
+    }
+
+    fn average(&self) -> f64 {
+        (self.done - self.initial) as f64 / self.start.elapsed().as_secs_f64()
+    }
+
+    fn draw(&mut self) {
+        if self.mode == ProgressMode::Quiet {
+            return;
+        }
+
+        let Some(len) = self.len else {
+            let status = format!(
+                "{}, {}/s (avg {}/s)",
+                bytes_to_hr(self.done as f64),
+                bytes_to_hr(self.current),
+                bytes_to_hr(self.average())
+            );
+
+            if self.mode == ProgressMode::Bar {
+                print!("\r{status}\x1b[K");
+                let _ = io::stdout().flush();
+            } else {
+                println!("{status}");
+            }
+
+            self.drawn = true;
+            return;
+        };
+
+        let fraction = if len == 0 {
+            1.
+        } else {
+            self.done as f64 / len as f64
+        };
+
+        let remaining = len.saturating_sub(self.done);
+        let average = self.average();
+
+        let eta = if remaining == 0 {
+            format_eta(0)
+        } else if average > 0. {
+            format_eta((remaining as f64 / average) as u64)
+        } else {
+            "--:--".to_string()
+        };
+
+        let status = format!(
+            "{:5.1}% {} of {}, {}/s (avg {}/s), {} left, ETA {}",
+            100. * fraction,
+            bytes_to_hr(self.done as f64),
+            bytes_to_hr(len as f64),
+            bytes_to_hr(self.current),
+            bytes_to_hr(average),
+            bytes_to_hr(remaining as f64),
+            eta
+        );
+
+        if self.mode == ProgressMode::Bar {
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+
+            print!(
+                "\r[{}{}] {}\x1b[K",
+                "#".repeat(filled),
+                ".".repeat(BAR_WIDTH - filled),
+                status
+            );
+            let _ = io::stdout().flush();
+        } else {
+            println!("{status}");
+        }
+
+        self.drawn = true;
+    }
+}
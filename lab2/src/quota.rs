@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Bytes uploaded per user and per client address, counted against their
+/// quotas. Finished uploads are recorded in a file so the totals survive
+/// restarts; uploads in progress are reserved up front so that concurrent
+/// ones can't overshoot a quota together.
+pub struct Ledger {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    used: HashMap<String, u64>,
+    reserved: HashMap<String, u64>,
+}
+
+impl State {
+    fn taken(&self, key: &str) -> u64 {
+        self.used.get(key).copied().unwrap_or(0) + self.reserved.get(key).copied().unwrap_or(0)
+    }
+
+    fn release(&mut self, key: &str, len: u64) {
+        if let Some(reserved) = self.reserved.get_mut(key) {
+            *reserved -= len;
+
+            if *reserved == 0 {
+                self.reserved.remove(key);
+            }
+        }
+    }
+}
+
+impl Ledger {
+    /// Loads the totals from `path`, lines of `key bytes`. A missing file
+    /// means nothing was uploaded yet.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut used = HashMap::new();
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some((key, bytes)) = line.rsplit_once(' ') {
+                        if let Ok(bytes) = bytes.parse() {
+                            used.insert(key.to_string(), bytes);
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(Self {
+            path,
+            state: Mutex::new(State {
+                used,
+                reserved: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Reserves `len` bytes against every `(key, quota)` in `quotas`. If that
+    /// would exceed one of them, nothing is reserved and the key is returned
+    /// with the bytes it has left.
+    pub fn reserve(
+        self: &Arc<Self>,
+        quotas: &[(String, u64)],
+        len: u64,
+    ) -> Result<Reservation, (String, u64)> {
+        let mut state = self.state.lock().unwrap();
+
+        for (key, quota) in quotas {
+            let taken = state.taken(key);
+
+            if taken.saturating_add(len) > *quota {
+                return Err((key.clone(), quota.saturating_sub(taken)));
+            }
+        }
+
+        for (key, _) in quotas {
+            *state.reserved.entry(key.clone()).or_default() += len;
+        }
+
+        Ok(Reservation {
+            ledger: self.clone(),
+            quotas: quotas.to_vec(),
+            len,
+            committed: false,
+        })
+    }
+
+    fn save(&self, state: &State) -> io::Result<()> {
+        let mut text = String::new();
+
+        for (key, bytes) in &state.used {
+            let _ = writeln!(text, "{key} {bytes}");
+        }
+
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, text)?;
+        fs::rename(temp, &self.path)
+    }
+}
+
+/// Bytes set aside for an upload in progress, given back when dropped
+/// unless the upload is committed.
+pub struct Reservation {
+    ledger: Arc<Ledger>,
+    quotas: Vec<(String, u64)>,
+    len: u64,
+    committed: bool,
+}
+
+impl Reservation {
+    /// Reserves `len` more bytes, for uploads whose length only becomes
+    /// known as they arrive. Fails like `Ledger::reserve`.
+    pub fn grow(&mut self, len: u64) -> Result<(), (String, u64)> {
+        let mut state = self.ledger.state.lock().unwrap();
+
+        for (key, quota) in &self.quotas {
+            let taken = state.taken(key);
+
+            if taken.saturating_add(len) > *quota {
+                return Err((key.clone(), quota.saturating_sub(taken)));
+            }
+        }
+
+        for (key, _) in &self.quotas {
+            *state.reserved.entry(key.clone()).or_default() += len;
+        }
+
+        self.len += len;
+
+        Ok(())
+    }
+
+    /// Records the upload as stored for good.
+    pub fn commit(mut self) -> io::Result<()> {
+        let ledger = self.ledger.clone();
+        let mut state = ledger.state.lock().unwrap();
+
+        for (key, _) in &self.quotas {
+            state.release(key, self.len);
+            *state.used.entry(key.clone()).or_default() += self.len;
+        }
+
+        self.committed = true;
+
+        ledger.save(&state)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        let mut state = self.ledger.state.lock().unwrap();
+
+        for (key, _) in &self.quotas {
+            state.release(key, self.len);
+        }
+    }
+}
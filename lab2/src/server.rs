@@ -1,159 +1,2706 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use std::mem::MaybeUninit;
 use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 use std::time::Instant;
+use std::time::UNIX_EPOCH;
 
+use rustls::ServerConfig;
+use rustls::ServerConnection;
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde::Serialize;
 use serde_binary::binary_stream::Endian;
+use sha2::Digest;
+use sha2::Sha256;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::SockAddr;
 use socket2::Socket;
 use socket2::Type;
 
+use crate::auth::Account;
+use crate::auth::Accounts;
 use crate::bytes_to_hr;
+use crate::compress::read_chunk;
+use crate::compress::Decoder;
+use crate::dedup;
+use crate::delta;
 use crate::format_sockaddr;
+use crate::hash_prefix;
+use crate::hook;
+use crate::hook::Hooks;
+use crate::is_timeout;
+use crate::limit::MinRate;
+use crate::limit::RateLimit;
+use crate::meta;
+use crate::quota::Ledger;
+use crate::quota::Reservation;
+use crate::read_message;
+use crate::tls::Stream;
+use crate::to_hex;
+use crate::udp;
+use crate::write_message;
+use crate::zerocopy;
+use crate::zerocopy::Splice;
+use crate::AuthResponse;
+use crate::Capability;
+use crate::Codec;
+use crate::ConflictPolicy;
+use crate::Credentials;
+use crate::DeltaComplete;
+use crate::DeltaOp;
+use crate::DeltaSignature;
+use crate::DownloadResponse;
+use crate::FileEntry;
+use crate::FileMetadata;
+use crate::Hello;
+use crate::HelloResponse;
+use crate::ListResponse;
+use crate::ManifestEntry;
+use crate::ManifestResponse;
+use crate::RangeRequest;
+use crate::RangeResponse;
+use crate::Rejection;
+use crate::Request;
+use crate::ResumeDecision;
+use crate::StreamComplete;
+use crate::StreamRequest;
+use crate::TransferDigest;
+use crate::Verdict;
+use crate::HELLO_MAGIC;
+use crate::MIN_PROTOCOL_VERSION;
+use crate::PROTOCOL_VERSION;
 
 use super::TransferComplete;
 use super::TransferRequest;
 use super::TransferResponse;
 
+/// Threads refusing connections as busy, so that a few clients slow to take
+/// their refusal don't hold up the others'.
+const REFUSERS: usize = 4;
+
+/// Longest a connection being refused may take to send its hello and read
+/// the answer.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Config {
+    pub root: PathBuf,
+    pub quarantine: PathBuf,
+    pub conflict: ConflictPolicy,
+    pub tls: Option<Arc<ServerConfig>>,
+    /// With accounts set, clients must authenticate and each user gets a
+    /// subdirectory of `root` and `quarantine`.
+    pub accounts: Option<Accounts>,
+    /// Codecs clients may compress uploads with, besides `Codec::None`.
+    pub compression: Vec<Codec>,
+    /// Upload bandwidth caps in bytes per second, over all connections and
+    /// per connection.
+    pub limit: Option<u64>,
+    pub connection_limit: Option<u64>,
+    /// Receive uncompressed uploads with `splice` where possible.
+    pub zero_copy: bool,
+    /// Time a client gets from connecting until its request is in, TLS
+    /// handshake, hello and authentication included.
+    pub handshake_timeout: Option<Duration>,
+    /// Longest a read or write may block before the connection is dropped.
+    pub idle_timeout: Option<Duration>,
+    /// Uploads slower than this many bytes per second over
+    /// `limit::MIN_RATE_WINDOW` are dropped.
+    pub min_rate: Option<u64>,
+    /// Mode bits uploads may set, see `meta::DEFAULT_MODE_MASK`.
+    pub mode_mask: u32,
+    /// Whether `user.` extended attributes sent with uploads are applied.
+    pub xattrs: bool,
+    /// Largest file accepted, in bytes.
+    pub max_file_size: Option<u64>,
+    /// Bytes each user and each client address may upload in total.
+    pub user_quota: Option<u64>,
+    pub ip_quota: Option<u64>,
+    /// Store uploads deduplicated by content, see `dedup::OBJECTS_DIR`.
+    pub dedup: bool,
+    pub hooks: Hooks,
+}
+
 pub struct Server {
-    pub(crate) socket: Socket,
+    /// Created by `listen`, for the address family of the bind address.
+    pub(crate) socket: Option<Socket>,
+    /// Created by `listen_udp`, to take clients over UDP as well.
+    pub(crate) udp: Option<udp::Endpoint>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) transfers: Arc<Transfers>,
+    pub(crate) active: Arc<Active>,
+    pub(crate) limit: Option<Arc<RateLimit>>,
+    pub(crate) ledger: Arc<Ledger>,
 }
 
 pub struct Connection {
-    pub socket: Socket,
+    pub stream: Stream<ServerConnection>,
     pub addr: SockAddr,
+    pub user: Option<Account>,
+    /// Agreed on in the hello.
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) config: Arc<Config>,
+    pub(crate) transfers: Arc<Transfers>,
+    pub(crate) active: Arc<Active>,
+    pub(crate) ledger: Arc<Ledger>,
+    /// The server-wide limit and this connection's own, if set.
+    pub(crate) limits: Vec<Arc<RateLimit>>,
+    pub(crate) min_rate: Option<MinRate>,
+    /// End of the handshake, until the request has been read.
+    pub(crate) deadline: Option<Instant>,
+    /// Size of the worker pool serving the connection, if it's in one.
+    pub(crate) workers: Option<usize>,
+    pub(crate) root: PathBuf,
+    pub(crate) quarantine: PathBuf,
 }
 
-impl Connection {
-    fn new(socket: Socket, addr: SockAddr) -> Self {
-        Self { socket, addr }
-    }
+/// A file being received over several connections. Each range is written
+/// straight into the temp file at its offset.
+pub(crate) struct ParallelTransfer {
+    name: String,
+    root: PathBuf,
+    user: Option<String>,
+    len: u64,
+    file: File,
+    /// `(offset, len, done)` of every range a connection has claimed.
+    ranges: Mutex<Vec<(u64, u64, bool)>>,
+    /// Bytes arrived over all range connections, to tell a slow upload
+    /// from a stalled one.
+    received: AtomicU64,
+}
 
-    fn send<T: Serialize>(&mut self, value: &T) -> io::Result<usize> {
-        self.socket
-            .write(&serde_binary::to_vec(value, Endian::Big).unwrap())
+/// Parallel uploads in progress, by transfer ID.
+pub(crate) type Transfers = Mutex<HashMap<u64, Arc<ParallelTransfer>>>;
+
+/// Temp files some connection is writing to right now, so that a second
+/// upload of the same name neither resumes nor overwrites one in flight.
+pub(crate) type Active = Mutex<HashSet<PathBuf>>;
+
+/// Reads from a connection's stream, failing once `deadline` has passed
+/// however slowly the bytes trickle in.
+struct Deadline<'a> {
+    stream: &'a mut Stream<ServerConnection>,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "handshake timed out");
+        let left = self.deadline.saturating_duration_since(Instant::now());
+
+        if left.is_zero() {
+            return Err(timed_out());
+        }
+
+        self.stream.socket.set_read_timeout(Some(left))?;
+
+        match self.stream.read(buf) {
+            Err(err) if is_timeout(&err) => Err(timed_out()),
+            result => result,
+        }
     }
+}
 
-    fn recv<T: DeserializeOwned>(&mut self) -> io::Result<T> {
-        let mut buffer = [0u8; 8192];
-        let read = self.socket.read(&mut buffer)?;
-        Ok(serde_binary::from_slice(&buffer[..read], Endian::Big).unwrap())
+/// Marks a temp file as being written to until dropped.
+pub(crate) struct Claim {
+    active: Arc<Active>,
+    path: PathBuf,
+}
+
+impl Claim {
+    fn new(active: &Arc<Active>, path: PathBuf) -> Option<Self> {
+        if !active.lock().unwrap().insert(path.clone()) {
+            return None;
+        }
+
+        Some(Self {
+            active: active.clone(),
+            path,
+        })
     }
+}
 
-    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
-        self.socket.read(buffer)
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.path);
     }
+}
 
-    pub fn transfer(&mut self) -> io::Result<()> {
-        let mut buffer = [0u8; 8192];
+/// A newly created temp file: the name it will be stored as, the file, the
+/// conflict policy applied to get there and the claim on it.
+type Created = (String, File, Option<ConflictPolicy>, Claim);
 
-        let request: TransferRequest = self.recv()?;
+impl ParallelTransfer {
+    /// Claims `len` bytes at `offset` for a connection, unless that is out
+    /// of bounds or overlaps a range already claimed.
+    fn claim(&self, offset: u64, len: u64) -> bool {
+        let mut ranges = self.ranges.lock().unwrap();
 
-        let mut out = match File::options()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(PathBuf::from("uploads").join(&request.name))
+        let Some(end) = offset
+            .checked_add(len)
+            .filter(|&end| len > 0 && end <= self.len)
+        else {
+            return false;
+        };
+
+        // claimed ranges are within the file, so none of this overflows
+        if ranges
+            .iter()
+            .any(|&(start, n, _)| offset < start + n && start < end)
         {
-            Ok(file) => {
-                self.send(&TransferResponse::Success)?;
-                file
-            }
+            return false;
+        }
 
-            Err(_) => {
-                self.send(&TransferResponse::Failure)?;
-                return Ok(());
-            }
-        };
+        ranges.push((offset, len, false));
+        true
+    }
 
-        let start = Instant::now();
-        let mut timer = Instant::now();
-        let mut bytes_rcvd_3s = 0;
-        let mut flag = false;
+    fn finish(&self, offset: u64) {
+        let mut ranges = self.ranges.lock().unwrap();
 
-        let mut bytes_rcvd = 0;
+        if let Some(range) = ranges.iter_mut().find(|range| range.0 == offset) {
+            range.2 = true;
+        }
+    }
 
-        while bytes_rcvd < request.len {
-            let rcvd = self.read(&mut buffer)?;
+    fn release(&self, offset: u64) {
+        self.ranges
+            .lock()
+            .unwrap()
+            .retain(|range| range.0 != offset);
+    }
 
-            if rcvd == 0 {
-                println!("{} closed connection abruptly", format_sockaddr(&self.addr));
-                return Ok(());
-            }
+    fn complete(&self) -> bool {
+        let ranges = self.ranges.lock().unwrap();
 
-            out.write(&buffer[..rcvd])?;
+        ranges.iter().all(|range| range.2)
+            && ranges.iter().map(|range| range.1).sum::<u64>() == self.len
+    }
+}
 
-            bytes_rcvd += rcvd as u64;
-            bytes_rcvd_3s += rcvd as u64;
+fn transfer_id() -> io::Result<u64> {
+    let mut id = [0u8; 8];
 
-            if timer.elapsed().as_secs() >= 3 {
-                println!(
-                    "{} [{:.1}%]: (last 3 seconds) {}/s (session) {}/s",
-                    request.name,
-                    100. * bytes_rcvd as f64 / request.len as f64,
-                    bytes_to_hr(bytes_rcvd_3s as f64 / timer.elapsed().as_secs_f64()),
-                    bytes_to_hr(bytes_rcvd as f64 / start.elapsed().as_secs_f64())
-                );
+    rustls::crypto::ring::default_provider()
+        .secure_random
+        .fill(&mut id)
+        .map_err(|_| io::Error::other("can't generate a transfer id"))?;
+
+    Ok(u64::from_be_bytes(id))
+}
+
+/// Stored next to a partially received file so that a later request for the
+/// same name and length can pick up where the previous one stopped. `name` is
+/// what the file was actually stored as, which differs from the requested
+/// name after an auto-rename.
+#[derive(Serialize, Deserialize)]
+struct PartialUpload {
+    len: u64,
+    name: String,
+}
+
+/// Bookkeeping files live hidden next to the file they describe, so
+/// `dir/name` has its temp file at `dir/.name.part`.
+fn sidecar_path(root: &Path, name: &str, ext: &str) -> PathBuf {
+    let path = Path::new(name);
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    root.join(path.parent().unwrap_or(Path::new("")))
+        .join(format!(".{file_name}.{ext}"))
+}
+
+fn join_relative(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+impl PartialUpload {
+    fn path(root: &Path, name: &str) -> PathBuf {
+        sidecar_path(root, name, "resume")
+    }
+
+    fn load(root: &Path, name: &str) -> Option<Self> {
+        let bytes = fs::read(Self::path(root, name)).ok()?;
+        serde_binary::from_slice(&bytes, Endian::Big).ok()
+    }
 
-                timer = Instant::now();
-                bytes_rcvd_3s = 0;
-                flag = true;
+    fn store(&self, root: &Path, name: &str) -> io::Result<()> {
+        fs::write(
+            Self::path(root, name),
+            serde_binary::to_vec(self, Endian::Big).unwrap(),
+        )
+    }
+
+    fn remove(root: &Path, name: &str) {
+        let _ = fs::remove_file(Self::path(root, name));
+    }
+}
+
+/// An upload stopped while its data was arriving, for breaking a limit.
+#[derive(Debug)]
+struct Refused {
+    reason: Rejection,
+    message: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.reason, self.message)
+    }
+}
+
+impl Error for Refused {}
+
+fn refused(reason: Rejection, message: String) -> io::Error {
+    io::Error::other(Refused { reason, message })
+}
+
+/// Why a stream upload failed with `err`, if it was for breaking a limit
+/// rather than a broken connection.
+fn refusal(err: &io::Error) -> Option<(Rejection, String)> {
+    if err.kind() == io::ErrorKind::StorageFull {
+        return Some((Rejection::DiskFull, err.to_string()));
+    }
+
+    let refused = err.get_ref()?.downcast_ref::<Refused>()?;
+    Some((refused.reason, refused.message.clone()))
+}
+
+/// Where received file data goes: the temp file and the running hash.
+/// Anything past `limit` is refused, so a bad compressed stream can't grow
+/// the file beyond the announced length. Streams have no announced length;
+/// for them `limit` is the size limit and `reservation` grows with the data.
+struct Sink<'a> {
+    file: &'a mut File,
+    hasher: &'a mut Sha256,
+    written: u64,
+    limit: u64,
+    reservation: Option<&'a mut Reservation>,
+}
+
+impl Write for Sink<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len() as u64;
+
+        match &mut self.reservation {
+            Some(_) if self.written + len > self.limit => {
+                return Err(refused(
+                    Rejection::FileTooLarge,
+                    format!("over the limit of {}", bytes_to_hr(self.limit as f64)),
+                ));
             }
+            Some(reservation) => reservation.grow(len).map_err(|(key, _)| {
+                refused(Rejection::QuotaExceeded, format!("quota of {key} exceeded"))
+            })?,
+            None if self.written + len > self.limit => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("more data than the announced {} bytes", self.limit),
+                ));
+            }
+            None => {}
+        }
+
+        self.file.write_all(buf)?;
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rate(bytes: u64, since: Instant) -> String {
+    bytes_to_hr(bytes as f64 / since.elapsed().as_secs_f64())
+}
+
+/// Speed statistics of an upload. `wire` counts the bytes as they came off
+/// the socket, which is less than the file bytes when compressed. Streams
+/// have no `len` to report progress against.
+struct Stats {
+    name: String,
+    len: Option<u64>,
+    offset: u64,
+    codec: Codec,
+    start: Instant,
+    timer: Instant,
+    file: u64,
+    wire: u64,
+    file_3s: u64,
+    wire_3s: u64,
+    reported: bool,
+}
+
+impl Stats {
+    fn new(name: &str, len: Option<u64>, offset: u64, codec: Codec) -> Self {
+        Self {
+            name: name.to_string(),
+            len,
+            offset,
+            codec,
+            start: Instant::now(),
+            timer: Instant::now(),
+            file: 0,
+            wire: 0,
+            file_3s: 0,
+            wire_3s: 0,
+            reported: false,
+        }
+    }
+
+    fn speed(&self, file: u64, wire: u64, since: Instant) -> String {
+        if self.codec == Codec::None {
+            format!("{}/s", rate(file, since))
+        } else {
+            format!("{}/s ({}/s on wire)", rate(file, since), rate(wire, since))
+        }
+    }
+
+    fn record(&mut self, file: u64, wire: u64) {
+        self.file += file;
+        self.wire += wire;
+        self.file_3s += file;
+        self.wire_3s += wire;
+
+        if self.timer.elapsed().as_secs() >= 3 {
+            let done = match self.len {
+                Some(len) => format!(
+                    "{:.1}%",
+                    100. * (self.offset + self.file) as f64 / len as f64
+                ),
+                None => bytes_to_hr(self.file as f64),
+            };
+
+            println!(
+                "{} [{}]: (last 3 seconds) {} (session) {}",
+                self.name,
+                done,
+                self.speed(self.file_3s, self.wire_3s, self.timer),
+                self.speed(self.file, self.wire, self.start)
+            );
+
+            self.timer = Instant::now();
+            self.file_3s = 0;
+            self.wire_3s = 0;
+            self.reported = true;
         }
+    }
 
-        if !flag {
+    fn finish(&self) {
+        if !self.reported {
             println!(
-                "{}: (session) {}/s",
-                request.name,
-                bytes_to_hr(bytes_rcvd as f64 / start.elapsed().as_secs_f64())
+                "{}: (session) {}",
+                self.name,
+                self.speed(self.file, self.wire, self.start)
             );
         }
 
         println!(
             "{}: received {} ({} bytes)",
-            request.name,
-            bytes_to_hr(bytes_rcvd as f64),
-            bytes_rcvd
+            self.name,
+            bytes_to_hr(self.file as f64),
+            self.file
         );
 
-        if bytes_rcvd < request.len {
-            let _ = std::fs::remove_file(PathBuf::from("uploads").join(&request.name));
+        if self.codec != Codec::None {
+            println!(
+                "{}: {} ({} bytes) on wire with {}, ratio {:.2}",
+                self.name,
+                bytes_to_hr(self.wire as f64),
+                self.wire,
+                self.codec,
+                self.file as f64 / self.wire.max(1) as f64
+            );
         }
+    }
+}
 
-        self.send(&TransferComplete::new(bytes_rcvd))?;
+/// Uploads are written to a hidden file next to their final path and only
+/// renamed into place once complete and verified.
+fn temp_path(root: &Path, name: &str) -> PathBuf {
+    sidecar_path(root, name, "part")
+}
 
-        println!("{}: connection closed", format_sockaddr(&self.addr));
+/// SHA-256 of a committed upload, kept so listings and downloads don't have
+/// to rehash the file.
+fn hash_path(root: &Path, name: &str) -> PathBuf {
+    sidecar_path(root, name, "sha256")
+}
 
-        Ok(())
+fn modified_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Removes temp files that no resume record points to, resume records whose
+/// temp file is gone, and hashes of files that no longer exist, in `dir` and
+/// everything below it.
+fn remove_orphans(root: &Path, dir: &str) -> io::Result<()> {
+    let mut parts = Vec::new();
+    let mut resumes = Vec::new();
+    let mut hashes = Vec::new();
+    let mut subdirs = Vec::new();
+
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let Some(hidden) = file_name.strip_prefix('.') else {
+            if entry.file_type()?.is_dir() {
+                subdirs.push(join_relative(dir, &file_name));
+            }
+
+            continue;
+        };
+
+        if let Some(name) = hidden.strip_suffix(".part") {
+            parts.push(join_relative(dir, name));
+        } else if let Some(name) = hidden.strip_suffix(".resume") {
+            resumes.push(join_relative(dir, name));
+        } else if let Some(name) = hidden.strip_suffix(".sha256") {
+            hashes.push(join_relative(dir, name));
+        } else if hidden.ends_with(".link") {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    for name in hashes {
+        if !root.join(&name).exists() {
+            fs::remove_file(hash_path(root, &name))?;
+        }
+    }
+
+    let mut referenced = Vec::new();
+
+    for requested in resumes {
+        match PartialUpload::load(root, &requested) {
+            Some(partial) if parts.contains(&partial.name) => referenced.push(partial.name),
+            _ => {
+                println!("removing stale resume record for {requested}");
+                PartialUpload::remove(root, &requested);
+            }
+        }
+    }
+
+    for name in parts {
+        if !referenced.contains(&name) {
+            println!("removing orphaned temp file for {name}");
+            fs::remove_file(temp_path(root, &name))?;
+        }
+    }
+
+    for subdir in subdirs {
+        remove_orphans(root, &subdir)?;
     }
+
+    Ok(())
 }
 
-impl Server {
-    pub fn new() -> io::Result<Self> {
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-        Ok(Self { socket })
+fn list_files(root: &Path, dir: &str, files: &mut Vec<FileEntry>) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let metadata = entry.metadata()?;
+
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        let name = join_relative(dir, &file_name);
+
+        if metadata.is_dir() {
+            list_files(root, &name, files)?;
+        } else if metadata.is_file() {
+            files.push(FileEntry {
+                hash: fs::read(hash_path(root, &name)).ok(),
+                len: metadata.len(),
+                modified: modified_secs(&metadata),
+                name,
+            });
+        }
     }
 
-    pub fn listen(&self, port: u16) -> io::Result<()> {
-        self.socket.bind(&SockAddr::from(SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            port,
-        )))?;
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the file system of `path`.
+fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
 
-        self.socket.listen(10)
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is NUL-terminated and `stat` has room for the result.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
     }
 
-    pub fn accept(&self) -> io::Result<Connection> {
-        let (sock, addr) = self.socket.accept()?;
-        Ok(Connection::new(sock, addr))
+    // SAFETY: statvfs succeeded, so it filled in `stat`.
+    let stat = unsafe { stat.assume_init() };
+
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// The capability `request` can't be served without, if any.
+fn required(request: &Request) -> Option<Capability> {
+    match request {
+        Request::UploadTree { .. } => Some(Capability::Tree),
+        Request::ParallelUpload { .. } | Request::Range(_) => Some(Capability::Parallel),
+        Request::UploadStream(_) => Some(Capability::Stream),
+        Request::UploadDelta(_) => Some(Capability::Delta),
+        Request::UploadHashed { .. } => Some(Capability::Dedup),
+        _ => None,
+    }
+}
+
+/// Accepts only a plain file name: no separators, no `.`/`..`, no leading dot
+/// (those are reserved for the server's own bookkeeping files) and no control
+/// characters.
+pub fn sanitize_name(name: &str) -> Option<&str> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control);
+
+    valid.then_some(name)
+}
+
+/// Accepts a relative `/`-separated path whose components each pass
+/// [`sanitize_name`], so it can only ever point below the upload root.
+pub fn sanitize_path(path: &str) -> Option<&str> {
+    let valid = path.len() <= 4096 && path.split('/').all(|name| sanitize_name(name).is_some());
+
+    valid.then_some(path)
+}
+
+fn with_suffix(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+
+    let file_name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => {
+            format!("{}-{n}.{}", stem.to_string_lossy(), ext.to_string_lossy())
+        }
+        _ => format!(
+            "{}-{n}",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ),
+    };
+
+    match name.rsplit_once('/') {
+        Some((dir, _)) => format!("{dir}/{file_name}"),
+        None => file_name,
+    }
+}
+
+fn create_new(path: &Path) -> io::Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+impl Connection {
+    fn new(
+        stream: Stream<ServerConnection>,
+        addr: SockAddr,
+        config: Arc<Config>,
+        transfers: Arc<Transfers>,
+        active: Arc<Active>,
+        ledger: Arc<Ledger>,
+        limit: Option<Arc<RateLimit>>,
+    ) -> Self {
+        let limits = limit
+            .into_iter()
+            .chain(
+                config
+                    .connection_limit
+                    .map(|rate| Arc::new(RateLimit::new(rate))),
+            )
+            .collect();
+
+        Self {
+            stream,
+            addr,
+            user: None,
+            capabilities: Vec::new(),
+            root: config.root.clone(),
+            quarantine: config.quarantine.clone(),
+            min_rate: config.min_rate.map(MinRate::new),
+            deadline: None,
+            workers: None,
+            config,
+            transfers,
+            active,
+            ledger,
+            limits,
+        }
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    fn claim(&self, name: &str) -> Option<Claim> {
+        Claim::new(&self.active, temp_path(&self.root, name))
+    }
+
+    fn send<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        write_message(&mut self.stream, value)
+    }
+
+    fn recv<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        match self.deadline {
+            Some(deadline) => read_message(&mut Deadline {
+                stream: &mut self.stream,
+                deadline,
+            }),
+            None => read_message(&mut self.stream),
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buffer)
+    }
+
+    /// Sleeps as long as needed to keep `bytes` within the bandwidth limits,
+    /// then fails if the upload has fallen below the minimum rate.
+    fn throttle(&mut self, bytes: u64) -> io::Result<()> {
+        for limit in &self.limits {
+            limit.consume(bytes);
+        }
+
+        match &mut self.min_rate {
+            Some(min_rate) => min_rate.record(bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts measuring the upload rate afresh as data is about to arrive.
+    fn start_receiving(&mut self) {
+        if let Some(min_rate) = &mut self.min_rate {
+            min_rate.reset();
+        }
+    }
+
+    fn log_rejection(&self, reason: Rejection, message: &str) {
+        println!(
+            "{}: rejected ({reason}): {message}",
+            format_sockaddr(&self.addr)
+        );
+    }
+
+    fn reject(&mut self, reason: Rejection, message: String) -> io::Result<()> {
+        self.log_rejection(reason, &message);
+        self.send(&TransferResponse::Failure { reason, message })
+    }
+
+    fn quarantine(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.quarantine.join(name);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::rename(temp_path(&self.root, name), &path)?;
+
+        Ok(path)
+    }
+
+    /// Creates the temp file for a new upload, applying the conflict policy
+    /// if `name` is already taken, either by a finished file or by an upload
+    /// in progress. Returns `None` if the upload is rejected.
+    fn create(&self, name: &str) -> io::Result<Option<Created>> {
+        let root = &self.root;
+
+        if let Some(parent) = root.join(name).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if !root.join(name).exists() {
+            match create_new(&temp_path(root, name)) {
+                Ok(file) => {
+                    if let Some(claim) = self.claim(name) {
+                        return Ok(Some((name.to_string(), file, None, claim)));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        match self.config.conflict {
+            ConflictPolicy::Reject => Ok(None),
+
+            ConflictPolicy::Overwrite => {
+                let Some(claim) = self.claim(name) else {
+                    return Ok(None);
+                };
+
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(temp_path(root, name))?;
+
+                Ok(Some((
+                    name.to_string(),
+                    file,
+                    Some(ConflictPolicy::Overwrite),
+                    claim,
+                )))
+            }
+
+            ConflictPolicy::Rename => {
+                let mut n = 1;
+
+                loop {
+                    let candidate = with_suffix(name, n);
+
+                    if root.join(&candidate).exists() {
+                        n += 1;
+                        continue;
+                    }
+
+                    match create_new(&temp_path(root, &candidate)) {
+                        Ok(file) => match self.claim(&candidate) {
+                            Some(claim) => {
+                                return Ok(Some((
+                                    candidate,
+                                    file,
+                                    Some(ConflictPolicy::Rename),
+                                    claim,
+                                )))
+                            }
+                            None => n += 1,
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves the connection's request. Connections that time out are
+    /// dropped with the reason logged.
+    pub fn handle(&mut self) -> io::Result<()> {
+        match self.serve_request() {
+            Err(err) if is_timeout(&err) => {
+                // socket timeouts come as a bare EAGAIN
+                let reason = match (err.raw_os_error(), self.config.idle_timeout) {
+                    (Some(_), Some(idle)) => format!("idle for {}s", idle.as_secs()),
+                    _ => err.to_string(),
+                };
+
+                println!("{}: dropped, {reason}", format_sockaddr(&self.addr));
+
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn serve_request(&mut self) -> io::Result<()> {
+        let socket = &self.stream.socket;
+        socket.set_read_timeout(self.config.idle_timeout)?;
+        socket.set_write_timeout(self.config.idle_timeout)?;
+
+        self.deadline = self
+            .config
+            .handshake_timeout
+            .map(|timeout| Instant::now() + timeout);
+
+        if !self.hello()? {
+            return Ok(());
+        }
+
+        let mut request = self.recv()?;
+
+        if let Request::Authenticate(credentials) = &request {
+            if !self.authenticate(credentials)? {
+                return Ok(());
+            }
+
+            request = self.recv()?;
+        }
+
+        self.deadline = None;
+        self.stream
+            .socket
+            .set_read_timeout(self.config.idle_timeout)?;
+
+        if let Err(message) = self.authorize(&request) {
+            self.refuse(&request, Rejection::Unauthorized, message)?;
+        } else if let Some(capability) =
+            required(&request).filter(|capability| !self.supports(*capability))
+        {
+            let message = format!("{} was not agreed on", capability.name());
+            self.refuse(&request, Rejection::Incompatible, message)?;
+        } else {
+            match request {
+                // refused by authorize
+                Request::Authenticate(_) => {}
+                Request::Upload(request) => self.transfer(request)?,
+                Request::UploadTree { entries } => self.transfer_tree(entries)?,
+                Request::List => self.list()?,
+                Request::Download { name } => self.download(&name)?,
+                Request::ParallelUpload { request, streams } => {
+                    self.transfer_parallel(request, streams)?
+                }
+                Request::Range(request) => self.receive_range(request)?,
+                Request::UploadStream(request) => self.transfer_stream(request)?,
+                Request::UploadDelta(request) => self.transfer_delta(request)?,
+                Request::UploadHashed { request, hash } => self.transfer_hashed(request, hash)?,
+            }
+        }
+
+        println!("{}: connection closed", format_sockaddr(&self.addr));
+
+        Ok(())
+    }
+
+    /// Tells the client the server is busy, in answer to its hello.
+    fn refuse_busy(mut self) -> io::Result<()> {
+        let socket = &self.stream.socket;
+        socket.set_read_timeout(Some(REFUSAL_TIMEOUT))?;
+        socket.set_write_timeout(Some(REFUSAL_TIMEOUT))?;
+
+        let _: Hello = self.recv()?;
+        let message = "too many connections, try again later".to_string();
+        self.log_rejection(Rejection::ServerBusy, &message);
+
+        self.send(&HelloResponse::Failure {
+            reason: Rejection::ServerBusy,
+            message,
+        })
+    }
+
+    /// Reads the client's hello and settles on the protocol version and
+    /// capabilities. Returns whether the client may go on.
+    fn hello(&mut self) -> io::Result<bool> {
+        let hello: Hello = match self.recv() {
+            Ok(hello) => hello,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                // whatever this is, it wouldn't understand a response
+                self.log_rejection(Rejection::Incompatible, "no hello, not a lab2 client?");
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if hello.magic != HELLO_MAGIC {
+            self.log_rejection(Rejection::Incompatible, "no hello, not a lab2 client?");
+            return Ok(false);
+        }
+
+        if hello.version < MIN_PROTOCOL_VERSION {
+            let message = format!(
+                "protocol version {} is no longer supported, {MIN_PROTOCOL_VERSION} or later is required",
+                hello.version
+            );
+            self.log_rejection(Rejection::Incompatible, &message);
+
+            self.send(&HelloResponse::Failure {
+                reason: Rejection::Incompatible,
+                message,
+            })?;
+
+            return Ok(false);
+        }
+
+        let offered = self.offered();
+        self.capabilities = Capability::parse(&hello.capabilities)
+            .into_iter()
+            .filter(|capability| offered.contains(capability))
+            .collect();
+
+        self.send(&HelloResponse::Accepted {
+            version: hello.version.min(PROTOCOL_VERSION),
+            capabilities: Capability::names(&self.capabilities),
+        })?;
+
+        Ok(true)
+    }
+
+    /// What this server supports; compression only if some codec is enabled.
+    fn offered(&self) -> Vec<Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(|capability| match capability {
+                Capability::Compression => !self.config.compression.is_empty(),
+                Capability::Dedup => self.config.dedup,
+                Capability::Validation => self.config.hooks.validate.is_some(),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Checks `credentials` and switches to the user's directories. Returns
+    /// whether the client may go on.
+    fn authenticate(&mut self, credentials: &Credentials) -> io::Result<bool> {
+        let Some(accounts) = &self.config.accounts else {
+            self.send(&AuthResponse::Success {
+                user: "anonymous".to_string(),
+            })?;
+
+            return Ok(true);
+        };
+
+        let Some(account) = accounts.authenticate(credentials).cloned() else {
+            let message = "invalid credentials".to_string();
+            self.log_rejection(Rejection::Unauthorized, &message);
+
+            self.send(&AuthResponse::Failure {
+                reason: Rejection::Unauthorized,
+                message,
+            })?;
+
+            return Ok(false);
+        };
+
+        println!(
+            "{}: authenticated as {}",
+            format_sockaddr(&self.addr),
+            account.name
+        );
+
+        self.root = self.config.root.join(&account.name);
+        self.quarantine = self.config.quarantine.join(&account.name);
+
+        self.send(&AuthResponse::Success {
+            user: account.name.clone(),
+        })?;
+
+        self.user = Some(account);
+
+        Ok(true)
+    }
+
+    fn authorize(&self, request: &Request) -> Result<(), String> {
+        // only ever the first request, with or without accounts
+        if let Request::Authenticate(_) = request {
+            return Err("already authenticated".to_string());
+        }
+
+        if self.config.accounts.is_none() {
+            return Ok(());
+        }
+
+        let Some(user) = &self.user else {
+            return Err("authentication required".to_string());
+        };
+
+        match request {
+            Request::Upload(_)
+            | Request::UploadTree { .. }
+            | Request::ParallelUpload { .. }
+            | Request::Range(_)
+            | Request::UploadStream(_)
+            | Request::UploadDelta(_)
+            | Request::UploadHashed { .. }
+                if !user.write =>
+            {
+                Err(format!("{} may not upload", user.name))
+            }
+            Request::List | Request::Download { .. } if !user.read => {
+                Err(format!("{} may not read files", user.name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Answers `request` with a failure of whatever response type it expects.
+    fn refuse(&mut self, request: &Request, reason: Rejection, message: String) -> io::Result<()> {
+        self.log_rejection(reason, &message);
+
+        match request {
+            Request::Authenticate(_) => self.send(&AuthResponse::Failure { reason, message }),
+            Request::Upload(_)
+            | Request::ParallelUpload { .. }
+            | Request::UploadStream(_)
+            | Request::UploadDelta(_)
+            | Request::UploadHashed { .. } => {
+                self.send(&TransferResponse::Failure { reason, message })
+            }
+            Request::Range(_) => self.send(&RangeResponse::Failure { reason, message }),
+            Request::UploadTree { .. } => self.send(&ManifestResponse::Failure { reason, message }),
+            Request::List => self.send(&ListResponse::Failure { reason, message }),
+            Request::Download { .. } => self.send(&DownloadResponse::Failure { reason, message }),
+        }
+    }
+
+    fn list(&mut self) -> io::Result<()> {
+        let mut files = Vec::new();
+        list_files(&self.root, "", &mut files)?;
+
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if !self.supports(Capability::Checksums) {
+            for file in &mut files {
+                file.hash = None;
+            }
+        }
+
+        println!(
+            "{}: listed {} files",
+            format_sockaddr(&self.addr),
+            files.len()
+        );
+
+        self.send(&ListResponse::Success { files })
+    }
+
+    fn download(&mut self, name: &str) -> io::Result<()> {
+        let Some(name) = sanitize_path(name) else {
+            let message = format!("invalid file name {name:?}");
+            return self.send_download_failure(Rejection::NameInvalid, message);
+        };
+
+        let root = self.root.clone();
+
+        let file = match File::open(root.join(name)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let message = format!("{name} does not exist");
+                return self.send_download_failure(Rejection::NotFound, message);
+            }
+            Err(err) => {
+                let message = format!("can't open {name}: {err}");
+                return self.send_download_failure(Rejection::Internal, message);
+            }
+        };
+
+        let metadata = file.metadata()?;
+
+        // a directory opens fine, only reading it fails
+        if !metadata.is_file() {
+            let message = format!("{name} is not a file");
+            return self.send_download_failure(Rejection::NotFound, message);
+        }
+
+        let len = metadata.len();
+        let hash = fs::read(hash_path(&root, name))
+            .ok()
+            .filter(|_| self.supports(Capability::Checksums));
+
+        self.send(&DownloadResponse::Success { len, hash })?;
+
+        let start = Instant::now();
+        let sent = io::copy(&mut file.take(len), &mut self.stream)?;
+
+        println!(
+            "{}: sent {} to {} ({}/s)",
+            name,
+            bytes_to_hr(sent as f64),
+            format_sockaddr(&self.addr),
+            bytes_to_hr(sent as f64 / start.elapsed().as_secs_f64())
+        );
+
+        Ok(())
+    }
+
+    fn send_download_failure(&mut self, reason: Rejection, message: String) -> io::Result<()> {
+        self.log_rejection(reason, &message);
+        self.send(&DownloadResponse::Failure { reason, message })
+    }
+
+    /// Checks the whole manifest up front, then receives each entry in order
+    /// as a regular upload on this connection.
+    fn transfer_tree(&mut self, entries: Vec<ManifestEntry>) -> io::Result<()> {
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| sanitize_path(&entry.path).is_none())
+        {
+            let message = format!("invalid path {:?}", entry.path);
+            self.log_rejection(Rejection::NameInvalid, &message);
+
+            return self.send(&ManifestResponse::Failure {
+                reason: Rejection::NameInvalid,
+                message,
+            });
+        }
+
+        let Some(total) = entries
+            .iter()
+            .try_fold(0u64, |total, entry| total.checked_add(entry.len))
+        else {
+            let message = "manifest sizes add up to more than 16EiB".to_string();
+            self.log_rejection(Rejection::FileTooLarge, &message);
+
+            return self.send(&ManifestResponse::Failure {
+                reason: Rejection::FileTooLarge,
+                message,
+            });
+        };
+
+        println!(
+            "{}: receiving {} files ({})",
+            format_sockaddr(&self.addr),
+            entries.len(),
+            bytes_to_hr(total as f64)
+        );
+
+        // each file is admitted again as it comes, this only turns away
+        // trees that can't fit as a whole before any of it is sent
+        let admitted = entries
+            .iter()
+            .try_for_each(|entry| {
+                self.check_size(entry.len)
+                    .map_err(|(reason, message)| (reason, format!("{}: {message}", entry.path)))
+            })
+            .and_then(|()| self.reserve(total, 0));
+
+        if let Err((reason, message)) = admitted {
+            self.log_rejection(reason, &message);
+            return self.send(&ManifestResponse::Failure { reason, message });
+        }
+
+        self.send(&ManifestResponse::Accepted)?;
+
+        for entry in entries {
+            let request: TransferRequest = self.recv()?;
+
+            if request.name != entry.path || request.len != entry.len {
+                let message = format!("{} does not match the manifest", request.name);
+                return self.reject(Rejection::NameInvalid, message);
+            }
+
+            self.transfer(request)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads `sink.limit` bytes of raw file data. Returns false if the
+    /// client went away first.
+    fn receive_raw(&mut self, sink: &mut Sink, stats: &mut Stats) -> io::Result<bool> {
+        let mut buffer = [0u8; 8192];
+
+        while sink.written < sink.limit {
+            let chunk = buffer.len().min((sink.limit - sink.written) as usize);
+            let rcvd = self.read(&mut buffer[..chunk])?;
+
+            if rcvd == 0 {
+                return Ok(false);
+            }
+
+            self.throttle(rcvd as u64)?;
+            sink.write_all(&buffer[..rcvd])?;
+            stats.record(rcvd as u64, rcvd as u64);
+        }
+
+        Ok(true)
+    }
+
+    /// Like `receive_raw`, but moves the data from the socket into the file
+    /// in the kernel and hashes it from the page cache afterwards. Falls
+    /// back to `receive_raw` if the file doesn't support `splice`.
+    fn receive_spliced(&mut self, sink: &mut Sink, stats: &mut Stats) -> io::Result<bool> {
+        let Ok(mut pipe) = Splice::new() else {
+            return self.receive_raw(sink, stats);
+        };
+
+        let start = sink.file.stream_position()?;
+
+        while sink.written < sink.limit && pipe.usable() {
+            let chunk = (sink.limit - sink.written).min(zerocopy::CHUNK as u64) as usize;
+
+            let moved =
+                match pipe.splice(&self.stream.socket, sink.file, start + sink.written, chunk) {
+                    Ok(0) => return Ok(false),
+                    Ok(moved) => moved as u64,
+                    Err(err) if sink.written == 0 && zerocopy::is_unsupported(&err) => break,
+                    Err(err) => return Err(err),
+                };
+
+            self.throttle(moved)?;
+            sink.written += moved;
+            stats.record(moved, moved);
+        }
+
+        sink.file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut (&mut *sink.file).take(sink.written), sink.hasher)?;
+
+        if sink.written < sink.limit {
+            return self.receive_raw(sink, stats);
+        }
+
+        Ok(true)
+    }
+
+    /// Reads chunks of `codec` compressed data until the end marker,
+    /// decompressing into `sink`. Returns false if the client went away
+    /// first.
+    fn receive_compressed(
+        &mut self,
+        codec: Codec,
+        sink: &mut Sink,
+        stats: &mut Stats,
+    ) -> io::Result<bool> {
+        let mut decoder = Decoder::new(codec, &mut *sink)?;
+        let mut buffer = Vec::new();
+
+        loop {
+            let len = match read_chunk(&mut self.stream, &mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err),
+            };
+
+            self.throttle(4 + len as u64)?;
+
+            let written = decoder.get_ref().written;
+            decoder.write_all(&buffer[..len])?;
+            stats.record(decoder.get_ref().written - written, 4 + len as u64);
+        }
+
+        let written = decoder.get_ref().written;
+        let sink = decoder.finish()?;
+        stats.record(sink.written - written, 4);
+
+        Ok(true)
+    }
+
+    /// Reads chunks of raw data until the end marker into `sink`. Returns
+    /// false if the client went away first.
+    fn receive_chunks(&mut self, sink: &mut Sink, stats: &mut Stats) -> io::Result<bool> {
+        let mut buffer = Vec::new();
+
+        loop {
+            let len = match read_chunk(&mut self.stream, &mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err),
+            };
+
+            self.throttle(4 + len as u64)?;
+            sink.write_all(&buffer[..len])?;
+            stats.record(len as u64, 4 + len as u64);
+        }
+    }
+
+    /// Reads and drops chunks up to the end marker, so that a client still
+    /// sending a refused stream gets to read why.
+    fn drain_chunks(&mut self) {
+        let mut buffer = Vec::new();
+
+        while let Ok(len) = read_chunk(&mut self.stream, &mut buffer) {
+            if len == 0 {
+                break;
+            }
+        }
+    }
+
+    fn pick_codec(&self, codecs: &[Codec]) -> Codec {
+        codecs
+            .iter()
+            .copied()
+            .filter(|_| self.supports(Capability::Compression))
+            .find(|codec| *codec == Codec::None || self.config.compression.contains(codec))
+            .unwrap_or(Codec::None)
+    }
+
+    pub fn transfer(&mut self, request: TransferRequest) -> io::Result<()> {
+        let Some(requested) = sanitize_path(&request.name) else {
+            let message = format!("invalid file name {:?}", request.name);
+            return self.reject(Rejection::NameInvalid, message);
+        };
+
+        let root = self.root.clone();
+
+        let codec = self.pick_codec(&request.codecs);
+
+        let partial = PartialUpload::load(&root, requested)
+            .filter(|partial| partial.len == request.len)
+            .and_then(|partial| {
+                let claim = self.claim(&partial.name)?;
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .open(temp_path(&root, &partial.name))
+                    .ok()?;
+
+                Some((partial.name, file, claim))
+            });
+
+        let held = match &partial {
+            // a client that can't resume starts over, see below
+            Some((_, out, _)) if self.supports(Capability::Resume) => {
+                out.metadata()?.len().min(request.len)
+            }
+            _ => 0,
+        };
+
+        let mut reservation = match self.admit(request.len, held) {
+            Ok(reservation) => reservation,
+            Err((reason, message)) => return self.reject(reason, message),
+        };
+
+        let (name, mut out, offset, mut hasher, _claim) = match partial {
+            // a client that can't resume starts over in the same temp file
+            Some((name, out, claim)) if !self.supports(Capability::Resume) => {
+                self.send(&TransferResponse::Success {
+                    name: name.clone(),
+                    conflict: None,
+                    codec,
+                })?;
+
+                (name, out, 0, Sha256::new(), claim)
+            }
+
+            Some((name, mut out, claim)) => {
+                let prefix = hash_prefix(&mut out, held)?;
+                let hash = prefix.clone().finalize().to_vec();
+
+                self.send(&TransferResponse::Resume {
+                    name: name.clone(),
+                    offset: held,
+                    hash,
+                    codec,
+                })?;
+
+                match self.recv()? {
+                    ResumeDecision::Continue => (name, out, held, prefix, claim),
+                    // the whole file is written again, which the check above
+                    // didn't allow for; too late to refuse, so hang up
+                    ResumeDecision::Restart => {
+                        drop(reservation);
+
+                        reservation = match self.admit(request.len, 0) {
+                            Ok(reservation) => reservation,
+                            Err((reason, message)) => {
+                                self.log_rejection(reason, &message);
+                                return Err(io::Error::other(message));
+                            }
+                        };
+
+                        (name, out, 0, Sha256::new(), claim)
+                    }
+                }
+            }
+
+            None => {
+                let Some((name, out, conflict, claim)) = self.create_or_reject(requested)? else {
+                    return Ok(());
+                };
+
+                PartialUpload {
+                    len: request.len,
+                    name: name.clone(),
+                }
+                .store(&root, requested)?;
+
+                self.send(&TransferResponse::Success {
+                    name: name.clone(),
+                    conflict,
+                    codec,
+                })?;
+
+                (name, out, 0, Sha256::new(), claim)
+            }
+        };
+
+        if offset > 0 {
+            println!("{}: resuming at {} bytes", name, offset);
+        }
+
+        out.set_len(offset)?;
+        out.seek(SeekFrom::Start(offset))?;
+
+        let mut stats = Stats::new(&name, Some(request.len), offset, codec);
+        let mut sink = Sink {
+            file: &mut out,
+            hasher: &mut hasher,
+            written: 0,
+            limit: request.len - offset,
+            reservation: None,
+        };
+
+        self.start_receiving();
+
+        let received = match codec {
+            Codec::None if self.config.zero_copy && self.stream.tls.is_none() => {
+                self.receive_spliced(&mut sink, &mut stats)
+            }
+            Codec::None => self.receive_raw(&mut sink, &mut stats),
+            codec => self.receive_compressed(codec, &mut sink, &mut stats),
+        };
+
+        let bytes_rcvd = sink.written;
+
+        let complete = match received {
+            Ok(complete) => complete,
+            // a stalled upload isn't kept around for resume
+            Err(err) if is_timeout(&err) => {
+                drop(out);
+                let _ = fs::remove_file(temp_path(&root, &name));
+                PartialUpload::remove(&root, requested);
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if complete && bytes_rcvd < request.len - offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "compressed stream ended after {} of {} bytes",
+                    bytes_rcvd,
+                    request.len - offset
+                ),
+            ));
+        }
+
+        if !complete {
+            println!(
+                "{} closed connection abruptly, keeping {} bytes of {} for resume",
+                format_sockaddr(&self.addr),
+                offset + bytes_rcvd,
+                name
+            );
+            return Ok(());
+        }
+
+        stats.finish();
+
+        let digest: TransferDigest = self.recv()?;
+        let hash = hasher.finalize().to_vec();
+
+        PartialUpload::remove(&root, requested);
+
+        let stored = self.commit(&name, out, &hash, &digest.hash, request.metadata.as_ref())?;
+
+        if stored {
+            reservation.commit()?;
+        }
+
+        self.send(&TransferComplete::new(offset + bytes_rcvd, stored))
+    }
+
+    /// Receives an upload of unknown length, see `StreamRequest`. Limits are
+    /// checked as the data arrives; once one is broken the client is told,
+    /// and whatever else it sends is dropped. Streams can't be resumed, so
+    /// the temp file goes away with any failure.
+    fn transfer_stream(&mut self, request: StreamRequest) -> io::Result<()> {
+        let Some(requested) = sanitize_path(&request.name) else {
+            let message = format!("invalid file name {:?}", request.name);
+            return self.reject(Rejection::NameInvalid, message);
+        };
+
+        let root = self.root.clone();
+        let codec = self.pick_codec(&request.codecs);
+
+        let mut reservation = match self.reserve(0, 0) {
+            Ok(reservation) => reservation,
+            Err((reason, message)) => return self.reject(reason, message),
+        };
+
+        let Some((name, mut out, conflict, _claim)) = self.create_or_reject(requested)? else {
+            return Ok(());
+        };
+
+        self.send(&TransferResponse::Success {
+            name: name.clone(),
+            conflict,
+            codec,
+        })?;
+
+        let mut hasher = Sha256::new();
+        let mut stats = Stats::new(&name, None, 0, codec);
+        let mut sink = Sink {
+            file: &mut out,
+            hasher: &mut hasher,
+            written: 0,
+            limit: self.config.max_file_size.unwrap_or(u64::MAX),
+            reservation: Some(&mut reservation),
+        };
+
+        self.start_receiving();
+
+        let received = match codec {
+            Codec::None => self.receive_chunks(&mut sink, &mut stats),
+            codec => self.receive_compressed(codec, &mut sink, &mut stats),
+        };
+
+        let bytes_rcvd = sink.written;
+
+        let complete = match received {
+            Ok(complete) => complete,
+            Err(err) => {
+                drop(out);
+                let _ = fs::remove_file(temp_path(&root, &name));
+
+                let Some((reason, message)) = refusal(&err) else {
+                    return Err(err);
+                };
+
+                let message = format!("{name}: {message}");
+                self.log_rejection(reason, &message);
+                self.send(&StreamComplete::Failure { reason, message })?;
+                self.drain_chunks();
+                return Ok(());
+            }
+        };
+
+        if !complete {
+            drop(out);
+            let _ = fs::remove_file(temp_path(&root, &name));
+            println!(
+                "{} closed connection abruptly, dropping stream of {}",
+                format_sockaddr(&self.addr),
+                name
+            );
+            return Ok(());
+        }
+
+        stats.finish();
+
+        let digest: TransferDigest = match self.recv() {
+            Ok(digest) => digest,
+            Err(err) => {
+                drop(out);
+                let _ = fs::remove_file(temp_path(&root, &name));
+                return Err(err);
+            }
+        };
+
+        let hash = hasher.finalize().to_vec();
+        let stored = self.commit(&name, out, &hash, &digest.hash, request.metadata.as_ref())?;
+
+        if stored {
+            reservation.commit()?;
+        }
+
+        self.send(&StreamComplete::Success(TransferComplete::new(
+            bytes_rcvd, stored,
+        )))
+    }
+
+    /// Stores `request` as a link to the content with `hash` if the server
+    /// has it already, sparing the client from sending it. Otherwise, or
+    /// with the length not matching, it's a normal upload.
+    fn transfer_hashed(&mut self, request: TransferRequest, hash: Vec<u8>) -> io::Result<()> {
+        let object = dedup::find(&self.root, &hash)
+            .filter(|object| fs::metadata(object).is_ok_and(|m| m.len() == request.len));
+
+        let Some(object) = object.filter(|_| self.config.dedup) else {
+            return self.transfer(request);
+        };
+
+        let Some(requested) = sanitize_path(&request.name) else {
+            let message = format!("invalid file name {:?}", request.name);
+            return self.reject(Rejection::NameInvalid, message);
+        };
+
+        // takes no space, but counts against the quotas all the same
+        let reservation = match self
+            .check_size(request.len)
+            .and_then(|()| self.reserve(request.len, request.len))
+        {
+            Ok(reservation) => reservation,
+            Err((reason, message)) => return self.reject(reason, message),
+        };
+
+        let Some((name, out, conflict, _claim)) = self.create_or_reject(requested)? else {
+            return Ok(());
+        };
+
+        drop(out);
+        fs::remove_file(temp_path(&self.root, &name))?;
+
+        // the content passed before, but maybe not under this name
+        let mut upload = self.upload(object.clone(), &name, request.len, &hash);
+
+        if let Err(message) = self.config.hooks.validate(&upload) {
+            return self.reject(self.validation_failed(), format!("{name}: {message}"));
+        }
+
+        dedup::link(&object, &self.root.join(&name))?;
+        fs::write(hash_path(&self.root, &name), &hash)?;
+        File::open(&self.root)?.sync_all()?;
+        reservation.commit()?;
+
+        println!(
+            "{}: linked to stored content, sha256 {}",
+            name,
+            to_hex(&hash)
+        );
+
+        upload.path = self.root.join(&name);
+        self.config.hooks.uploaded(upload);
+
+        self.send(&TransferResponse::Linked { name, conflict })
+    }
+
+    /// Receives a new version of a file, rebuilt from the server's copy and
+    /// the data the client found missing from it, see `Request::UploadDelta`.
+    /// Like any upload, the result only replaces the copy once verified.
+    fn transfer_delta(&mut self, request: TransferRequest) -> io::Result<()> {
+        let Some(requested) = sanitize_path(&request.name) else {
+            let message = format!("invalid file name {:?}", request.name);
+            return self.reject(Rejection::NameInvalid, message);
+        };
+
+        let root = self.root.clone();
+
+        let reservation = match self.admit(request.len, 0) {
+            Ok(reservation) => reservation,
+            Err((reason, message)) => return self.reject(reason, message),
+        };
+
+        // the copy is the base even if the conflict policy picks a new name
+        let mut basis = File::open(root.join(requested))
+            .ok()
+            .filter(|file| file.metadata().is_ok_and(|metadata| metadata.is_file()));
+
+        let Some((name, mut out, conflict, _claim)) = self.create_or_reject(requested)? else {
+            return Ok(());
+        };
+
+        self.send(&TransferResponse::Success {
+            name: name.clone(),
+            conflict,
+            codec: Codec::None,
+        })?;
+
+        let basis_len = match &basis {
+            Some(basis) => basis.metadata()?.len(),
+            None => 0,
+        };
+
+        let block_size = delta::block_size(basis_len);
+        let blocks = match &mut basis {
+            Some(basis) => delta::signature(basis, block_size)?,
+            None => Vec::new(),
+        };
+
+        self.send(&DeltaSignature { block_size, blocks })?;
+
+        let mut hasher = Sha256::new();
+        let mut stats = Stats::new(&name, Some(request.len), 0, Codec::None);
+        let mut sink = Sink {
+            file: &mut out,
+            hasher: &mut hasher,
+            written: 0,
+            limit: request.len,
+            reservation: None,
+        };
+
+        self.start_receiving();
+
+        let received = self
+            .receive_delta(basis.as_ref(), basis_len, block_size, &mut sink, &mut stats)
+            .and_then(|reused| {
+                if sink.written < request.len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("delta rebuilt {} of {} bytes", sink.written, request.len),
+                    ));
+                }
+
+                Ok(reused)
+            });
+
+        let digest = received.and_then(|reused| Ok((reused, self.recv::<TransferDigest>()?)));
+
+        let (reused, digest) = match digest {
+            Ok(received) => received,
+            // a delta can't be resumed, nothing is kept
+            Err(err) => {
+                drop(out);
+                let _ = fs::remove_file(temp_path(&root, &name));
+                return Err(err);
+            }
+        };
+
+        stats.finish();
+
+        println!(
+            "{}: reused {} of the existing copy, {} sent",
+            name,
+            bytes_to_hr(reused as f64),
+            bytes_to_hr((request.len - reused) as f64)
+        );
+
+        let hash = hasher.finalize().to_vec();
+        let stored = self.commit(&name, out, &hash, &digest.hash, request.metadata.as_ref())?;
+
+        if stored {
+            reservation.commit()?;
+        }
+
+        self.send(&DeltaComplete {
+            len: request.len,
+            verified: stored,
+            reused,
+        })
+    }
+
+    /// Reads `DeltaOp`s up to `End` into `sink`, copying blocks from
+    /// `basis`, which is `basis_len` long. Returns the bytes copied.
+    fn receive_delta(
+        &mut self,
+        basis: Option<&File>,
+        basis_len: u64,
+        block_size: u64,
+        sink: &mut Sink,
+        stats: &mut Stats,
+    ) -> io::Result<u64> {
+        let blocks = basis_len.div_ceil(block_size);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut reused = 0;
+
+        loop {
+            match self.recv()? {
+                DeltaOp::Literal(data) => {
+                    self.throttle(data.len() as u64)?;
+                    sink.write_all(&data)?;
+                    stats.record(data.len() as u64, data.len() as u64);
+                }
+
+                DeltaOp::Copy { block, count } => {
+                    let valid = block.checked_add(count).is_some_and(|end| end <= blocks);
+
+                    let Some(basis) = basis.filter(|_| valid) else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("blocks {block}+{count} out of {blocks}"),
+                        ));
+                    };
+
+                    let start = block * block_size;
+                    let end = ((block + count) * block_size).min(basis_len);
+                    let mut offset = start;
+
+                    while offset < end {
+                        let len = buffer.len().min((end - offset) as usize);
+                        basis.read_exact_at(&mut buffer[..len], offset)?;
+                        sink.write_all(&buffer[..len])?;
+                        stats.record(len as u64, 0);
+                        offset += len as u64;
+                    }
+
+                    reused += end - start;
+
+                    // copying isn't the client being slow
+                    self.start_receiving();
+                }
+
+                DeltaOp::End => return Ok(reused),
+            }
+        }
+    }
+
+    /// Moves the received temp file into place if `hash` matches the
+    /// client's and the validation hook accepts it, or into quarantine if
+    /// not. The client's `metadata` is applied first, so the file shows up
+    /// with it, and hooks see it that way. Returns whether it was stored.
+    fn commit(
+        &mut self,
+        name: &str,
+        out: File,
+        hash: &[u8],
+        expected: &[u8],
+        metadata: Option<&FileMetadata>,
+    ) -> io::Result<bool> {
+        let root = self.root.clone();
+
+        if hash != expected {
+            drop(out);
+            let path = self.quarantine(name)?;
+            println!(
+                "{}: sha256 mismatch (client {}, server {}), moved to {}",
+                name,
+                to_hex(expected),
+                to_hex(hash),
+                path.display()
+            );
+
+            self.send_verdict(Verdict::Accepted)?;
+            return Ok(false);
+        }
+
+        if let Some(metadata) = metadata {
+            self.apply_metadata(name, &out, metadata);
+        }
+
+        out.sync_all()?;
+        let len = out.metadata()?.len();
+        drop(out);
+
+        let mut upload = self.upload(temp_path(&root, name), name, len, hash);
+
+        if let Err(message) = self.config.hooks.validate(&upload) {
+            let path = self.quarantine(name)?;
+            println!("{}: failed validation, moved to {}", name, path.display());
+
+            let reason = Rejection::ValidationFailed;
+            let message = format!("{name}: {message}");
+            self.log_rejection(reason, &message);
+            self.send_verdict(Verdict::Rejected { reason, message })?;
+            return Ok(false);
+        }
+
+        fs::write(hash_path(&root, name), hash)?;
+
+        if !self.config.dedup {
+            fs::rename(temp_path(&root, name), root.join(name))?;
+        } else if dedup::store(&root, &temp_path(&root, name), hash, &root.join(name))? {
+            println!("{name}: same content as a stored file, linked to it");
+        }
+
+        File::open(&root)?.sync_all()?;
+
+        println!("{}: sha256 {}", name, to_hex(hash));
+
+        upload.path = root.join(name);
+        self.config.hooks.uploaded(upload);
+        self.send_verdict(Verdict::Accepted)?;
+
+        Ok(true)
+    }
+
+    /// Describes an upload to hooks.
+    fn upload(&self, path: PathBuf, name: &str, len: u64, hash: &[u8]) -> hook::Upload {
+        hook::Upload {
+            path,
+            name: name.to_string(),
+            len,
+            hash: hash.to_vec(),
+            client: format_sockaddr(&self.addr),
+            user: self.user.as_ref().map(|user| user.name.clone()),
+        }
+    }
+
+    /// The reason a failed validation is given in a `Failure`, which clients
+    /// without `Capability::Validation` don't know.
+    fn validation_failed(&self) -> Rejection {
+        if self.supports(Capability::Validation) {
+            Rejection::ValidationFailed
+        } else {
+            Rejection::Internal
+        }
+    }
+
+    /// Tells clients that take one how an upload fared, see `Verdict`.
+    fn send_verdict(&mut self, verdict: Verdict) -> io::Result<()> {
+        if self.supports(Capability::Validation) {
+            self.send(&verdict)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks an upload of `len` bytes against the size limit, the quotas
+    /// and the free space, `held` bytes of it being on disk already. Returns
+    /// the quota reservation for it, or why it doesn't fit.
+    fn admit(&self, len: u64, held: u64) -> Result<Reservation, (Rejection, String)> {
+        self.check_size(len)?;
+        self.reserve(len, held)
+    }
+
+    fn check_size(&self, len: u64) -> Result<(), (Rejection, String)> {
+        match self.config.max_file_size {
+            Some(max) if len > max => Err((
+                Rejection::FileTooLarge,
+                format!(
+                    "{} is over the limit of {}",
+                    bytes_to_hr(len as f64),
+                    bytes_to_hr(max as f64)
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reserves `len` bytes against the quotas, if they and the free space
+    /// allow.
+    fn reserve(&self, len: u64, held: u64) -> Result<Reservation, (Rejection, String)> {
+        let mut quotas = Vec::new();
+
+        if let (Some(user), Some(quota)) = (&self.user, self.config.user_quota) {
+            quotas.push((format!("user:{}", user.name), quota));
+        }
+
+        if let (Some(addr), Some(quota)) = (self.addr.as_socket(), self.config.ip_quota) {
+            quotas.push((format!("ip:{}", addr.ip().to_canonical()), quota));
+        }
+
+        let reservation = self.ledger.reserve(&quotas, len).map_err(|(key, left)| {
+            (
+                Rejection::QuotaExceeded,
+                format!(
+                    "quota of {key} exceeded, {} left for {}",
+                    bytes_to_hr(left as f64),
+                    bytes_to_hr(len as f64)
+                ),
+            )
+        })?;
+
+        let needed = len - held;
+        let available = free_space(&self.config.root).map_err(|err| {
+            (
+                Rejection::Internal,
+                format!("can't check free space: {err}"),
+            )
+        })?;
+
+        if needed > available {
+            return Err((
+                Rejection::DiskFull,
+                format!(
+                    "{} needed, {} free",
+                    bytes_to_hr(needed as f64),
+                    bytes_to_hr(available as f64)
+                ),
+            ));
+        }
+
+        Ok(reservation)
+    }
+
+    /// Applies what the policy allows of `metadata`. Failing to is logged,
+    /// but doesn't fail the upload.
+    fn apply_metadata(&self, name: &str, out: &File, metadata: &FileMetadata) {
+        let stripped = metadata.mode & !self.config.mode_mask;
+
+        if stripped != 0 {
+            println!("{name}: stripped mode bits {stripped:o}");
+        }
+
+        if let Err(err) = meta::apply(out, metadata, self.config.mode_mask, self.config.xattrs) {
+            println!("{name}: can't apply metadata: {err}");
+        }
+    }
+
+    /// Runs `create`, answering the client with the matching failure if the
+    /// file can't be created.
+    fn create_or_reject(&mut self, name: &str) -> io::Result<Option<Created>> {
+        match self.create(name) {
+            Ok(Some(created)) => Ok(Some(created)),
+
+            Ok(None) => {
+                let message = format!("{name} already exists");
+                self.reject(Rejection::AlreadyExists, message)?;
+                Ok(None)
+            }
+
+            Err(err) if err.kind() == io::ErrorKind::StorageFull => {
+                self.reject(Rejection::DiskFull, err.to_string())?;
+                Ok(None)
+            }
+
+            Err(err) => {
+                let message = format!("can't create {name}: {err}");
+                self.reject(Rejection::Internal, message)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sets up a parallel upload and waits for its digest. By the time the
+    /// client sends it, every range connection has had its data
+    /// acknowledged.
+    fn transfer_parallel(&mut self, request: TransferRequest, streams: u32) -> io::Result<()> {
+        let Some(requested) = sanitize_path(&request.name) else {
+            let message = format!("invalid file name {:?}", request.name);
+            return self.reject(Rejection::NameInvalid, message);
+        };
+
+        let reservation = match self
+            .check_streams(streams)
+            .and_then(|()| self.admit(request.len, 0))
+        {
+            Ok(reservation) => reservation,
+            Err((reason, message)) => return self.reject(reason, message),
+        };
+
+        let Some((name, out, conflict, _claim)) = self.create_or_reject(requested)? else {
+            return Ok(());
+        };
+
+        // sized up front so ranges can be written in any order
+        if let Err(err) = out.set_len(request.len) {
+            drop(out);
+            let _ = fs::remove_file(temp_path(&self.root, &name));
+
+            let reason = if err.kind() == io::ErrorKind::StorageFull {
+                Rejection::DiskFull
+            } else {
+                Rejection::Internal
+            };
+
+            return self.reject(reason, err.to_string());
+        }
+
+        let id = transfer_id()?;
+
+        let transfer = Arc::new(ParallelTransfer {
+            name: name.clone(),
+            root: self.root.clone(),
+            user: self.user.as_ref().map(|user| user.name.clone()),
+            len: request.len,
+            file: out,
+            ranges: Mutex::new(Vec::new()),
+            received: AtomicU64::new(0),
+        });
+
+        self.transfers.lock().unwrap().insert(id, transfer.clone());
+
+        println!(
+            "{}: receiving {} ({}) over {} streams",
+            format_sockaddr(&self.addr),
+            name,
+            bytes_to_hr(request.len as f64),
+            streams
+        );
+
+        let start = Instant::now();
+
+        let digest = self
+            .send(&TransferResponse::Parallel {
+                id,
+                name: name.clone(),
+                conflict,
+            })
+            .and_then(|()| self.await_digest(&transfer));
+
+        self.transfers.lock().unwrap().remove(&id);
+
+        let digest = match digest {
+            Ok(digest) if transfer.complete() => digest,
+            Ok(_) => {
+                let _ = fs::remove_file(temp_path(&self.root, &name));
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("digest for {name} sent before all ranges arrived"),
+                ));
+            }
+            Err(err) => {
+                println!(
+                    "{}: parallel upload of {} aborted",
+                    format_sockaddr(&self.addr),
+                    name
+                );
+                let _ = fs::remove_file(temp_path(&self.root, &name));
+                return Err(err);
+            }
+        };
+
+        println!(
+            "{}: received {} ({} bytes) over {} streams, (session) {}/s",
+            name,
+            bytes_to_hr(request.len as f64),
+            request.len,
+            streams,
+            bytes_to_hr(request.len as f64 / start.elapsed().as_secs_f64())
+        );
+
+        let mut out = transfer.file.try_clone()?;
+        let hash = hash_prefix(&mut out, request.len)?.finalize().to_vec();
+        let stored = self.commit(&name, out, &hash, &digest.hash, request.metadata.as_ref())?;
+
+        if stored {
+            reservation.commit()?;
+        }
+
+        self.send(&TransferComplete::new(request.len, stored))
+    }
+
+    /// Makes sure a parallel upload over `streams` can't leave its ranges
+    /// waiting for a worker forever. Its own connection holds a worker until
+    /// all ranges are in, and so does every other parallel upload.
+    fn check_streams(&self, streams: u32) -> Result<(), (Rejection, String)> {
+        let Some(workers) = self.workers else {
+            return Ok(());
+        };
+
+        if streams as usize >= workers {
+            return Err((
+                Rejection::ServerBusy,
+                format!(
+                    "at most {} streams per upload, the server has {workers} workers",
+                    workers - 1
+                ),
+            ));
+        }
+
+        if self.transfers.lock().unwrap().len() + 1 >= workers {
+            return Err((
+                Rejection::ServerBusy,
+                "too many parallel uploads in progress, try again later".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the digest while the ranges arrive on other connections.
+    /// Only once none of them has seen data for the idle timeout does the
+    /// wait time out.
+    fn await_digest(&mut self, transfer: &ParallelTransfer) -> io::Result<TransferDigest> {
+        let mut seen = transfer.received.load(Ordering::Relaxed);
+
+        loop {
+            match self.stream.socket.peek(&mut [MaybeUninit::uninit()]) {
+                Ok(_) => return self.recv(),
+                Err(err) if is_timeout(&err) => {
+                    let received = transfer.received.load(Ordering::Relaxed);
+
+                    if received == seen {
+                        return Err(err);
+                    }
+
+                    seen = received;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn send_range_failure(&mut self, reason: Rejection, message: String) -> io::Result<()> {
+        self.log_rejection(reason, &message);
+        self.send(&RangeResponse::Failure { reason, message })
+    }
+
+    /// Receives one range of a parallel upload, writing it at its offset.
+    fn receive_range(&mut self, request: RangeRequest) -> io::Result<()> {
+        let transfer = self.transfers.lock().unwrap().get(&request.id).cloned();
+
+        let Some(transfer) = transfer else {
+            let message = format!("no transfer {:016x}", request.id);
+            return self.send_range_failure(Rejection::NotFound, message);
+        };
+
+        let user = self.user.as_ref().map(|user| user.name.clone());
+
+        if transfer.user != user || transfer.root != self.root {
+            let message = format!("transfer {:016x} belongs to another user", request.id);
+            return self.send_range_failure(Rejection::Unauthorized, message);
+        }
+
+        if !transfer.claim(request.offset, request.len) {
+            let message = format!(
+                "{}+{} is outside {} or overlaps another range",
+                request.offset, request.len, transfer.name
+            );
+            return self.send_range_failure(Rejection::InvalidRange, message);
+        }
+
+        self.send(&RangeResponse::Accepted)?;
+        self.start_receiving();
+
+        let mut buffer = [0u8; 8192];
+        let start = Instant::now();
+        let mut bytes_rcvd = 0;
+
+        while bytes_rcvd < request.len {
+            let chunk = buffer.len().min((request.len - bytes_rcvd) as usize);
+
+            let rcvd = match self.read(&mut buffer[..chunk]) {
+                Ok(0) => {
+                    transfer.release(request.offset);
+                    println!(
+                        "{} closed connection abruptly during a range of {}",
+                        format_sockaddr(&self.addr),
+                        transfer.name
+                    );
+                    return Ok(());
+                }
+                Ok(rcvd) => rcvd,
+                Err(err) => {
+                    transfer.release(request.offset);
+                    return Err(err);
+                }
+            };
+
+            transfer.received.fetch_add(rcvd as u64, Ordering::Relaxed);
+
+            if let Err(err) = self.throttle(rcvd as u64) {
+                transfer.release(request.offset);
+                return Err(err);
+            }
+
+            if let Err(err) = transfer
+                .file
+                .write_all_at(&buffer[..rcvd], request.offset + bytes_rcvd)
+            {
+                transfer.release(request.offset);
+                return Err(err);
+            }
+
+            bytes_rcvd += rcvd as u64;
+        }
+
+        transfer.finish(request.offset);
+
+        println!(
+            "{}: range {}..{} received, {}/s",
+            transfer.name,
+            request.offset,
+            request.offset + request.len,
+            bytes_to_hr(request.len as f64 / start.elapsed().as_secs_f64())
+        );
+
+        self.send(&TransferComplete::new(request.len, true))
+    }
+}
+
+impl Server {
+    pub fn new(config: Config) -> io::Result<Self> {
+        fs::create_dir_all(&config.root)?;
+        remove_orphans(&config.root, "")?;
+
+        Ok(Self {
+            socket: None,
+            udp: None,
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            active: Arc::new(Mutex::new(HashSet::new())),
+            limit: config.limit.map(|rate| Arc::new(RateLimit::new(rate))),
+            ledger: Arc::new(Ledger::load(config.root.join(".quota"))?),
+            config: Arc::new(config),
+        })
+    }
+
+    /// The address the server listens on, e.g. to find the port picked
+    /// for port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket()?
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an inet socket"))
+    }
+
+    fn socket(&self) -> io::Result<&Socket> {
+        self.socket
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not listening"))
+    }
+
+    /// Listens on `addr`. Bound to the IPv6 unspecified address `::`, the
+    /// socket accepts IPv4 clients as well where the system allows it.
+    pub fn listen(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        if addr.is_ipv6() {
+            socket.set_only_v6(false)?;
+        }
+
+        socket.bind(&SockAddr::from(addr))?;
+        socket.listen(10)?;
+
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    /// Also takes clients over UDP on `addr`, with the same dual-stack
+    /// behaviour as `listen`.
+    pub fn listen_udp(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.udp = Some(udp::Endpoint::bind(addr)?);
+
+        Ok(())
+    }
+
+    pub fn accept(&self) -> io::Result<Connection> {
+        let (sock, addr) = self.socket()?.accept()?;
+        self.connection(sock, addr)
+    }
+
+    /// Waits for a client over UDP, see `listen_udp`.
+    pub fn accept_udp(&self) -> io::Result<Connection> {
+        let endpoint = self
+            .udp
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not listening on udp"))?;
+
+        let (sock, addr) = endpoint.accept()?;
+        self.connection(sock, SockAddr::from(addr))
+    }
+
+    fn connection(&self, sock: Socket, addr: SockAddr) -> io::Result<Connection> {
+        let mut stream = Stream::new(sock);
+
+        if let Some(tls) = &self.config.tls {
+            stream.tls = Some(ServerConnection::new(tls.clone()).map_err(io::Error::other)?);
+        }
+
+        Ok(Connection::new(
+            stream,
+            addr,
+            self.config.clone(),
+            self.transfers.clone(),
+            self.active.clone(),
+            self.ledger.clone(),
+            self.limit.clone(),
+        ))
+    }
+
+    /// Accepts connections forever and hands them to `workers` threads. Up
+    /// to `queue` connections wait for a free worker, and as many beyond
+    /// that are refused as busy by `REFUSERS` threads; any more are dropped.
+    pub fn serve(&self, workers: usize, queue: usize) -> ! {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Connection>(queue);
+        let (refusals, refused) = mpsc::sync_channel::<Connection>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let refused = Arc::new(Mutex::new(refused));
+
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+
+            thread::spawn(move || loop {
+                let Ok(mut conn) = receiver.lock().unwrap().recv() else {
+                    return;
+                };
+
+                let addr = format_sockaddr(&conn.addr);
+
+                // a bug hit by one client mustn't cost the others a worker
+                match panic::catch_unwind(AssertUnwindSafe(|| conn.handle())) {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => eprintln!("error while transfering: {err}"),
+                    Err(_) => eprintln!("{addr}: worker panicked, connection dropped"),
+                }
+            });
+        }
+
+        for _ in 0..REFUSERS {
+            let refused = refused.clone();
+
+            thread::spawn(move || loop {
+                let Ok(conn) = refused.lock().unwrap().recv() else {
+                    return;
+                };
+
+                if let Err(err) = conn.refuse_busy() {
+                    eprintln!("error refusing connection: {err}");
+                }
+            });
+        }
+
+        let pool = Pool {
+            workers,
+            sender,
+            refusals,
+        };
+
+        thread::scope(|scope| {
+            if self.udp.is_some() {
+                let pool = &pool;
+
+                scope.spawn(move || loop {
+                    match self.accept_udp() {
+                        Ok(conn) => pool.dispatch(conn, " over udp"),
+                        Err(err) => {
+                            eprintln!("error accepting udp connection: {err}");
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                    }
+                });
+            }
+
+            loop {
+                let conn = match self.accept() {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        eprintln!("error accepting connection: {err}");
+                        // e.g. out of file descriptors, give connections time to close
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                };
+
+                pool.dispatch(conn, "");
+            }
+        })
+    }
+}
+
+/// Where `Server::serve` sends connections: to the workers, or when their
+/// queue is full, to the threads refusing them.
+struct Pool {
+    workers: usize,
+    sender: mpsc::SyncSender<Connection>,
+    refusals: mpsc::SyncSender<Connection>,
+}
+
+impl Pool {
+    /// Queues `conn` for a worker, or for refusal when the queue is full.
+    fn dispatch(&self, mut conn: Connection, via: &str) {
+        let addr = format_sockaddr(&conn.addr);
+        println!("new connection: {addr}{via}");
+
+        conn.workers = Some(self.workers);
+
+        if let Err(TrySendError::Full(conn)) = self.sender.try_send(conn) {
+            if let Err(TrySendError::Full(_)) = self.refusals.try_send(conn) {
+                println!("{addr}: dropped, too busy to even refuse it");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parallel(len: u64) -> ParallelTransfer {
+        ParallelTransfer {
+            name: "file".to_string(),
+            root: PathBuf::new(),
+            user: None,
+            len,
+            file: File::open("/dev/null").unwrap(),
+            ranges: Mutex::new(Vec::new()),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn claim_takes_disjoint_ranges_within_the_file() {
+        let transfer = parallel(100);
+
+        assert!(transfer.claim(0, 50));
+        assert!(transfer.claim(50, 50));
+        assert!(!transfer.complete());
+
+        transfer.finish(0);
+        transfer.finish(50);
+        assert!(transfer.complete());
+    }
+
+    #[test]
+    fn claim_refuses_overlaps() {
+        let transfer = parallel(100);
+
+        assert!(transfer.claim(20, 20));
+
+        for (offset, len) in [(20, 20), (0, 21), (39, 10), (25, 5), (10, 40)] {
+            assert!(!transfer.claim(offset, len), "{offset}+{len}");
+        }
+
+        assert!(transfer.claim(0, 20));
+        assert!(transfer.claim(40, 10));
+
+        transfer.release(20);
+        assert!(transfer.claim(25, 5));
+    }
+
+    #[test]
+    fn claim_refuses_ranges_out_of_bounds() {
+        let transfer = parallel(100);
+
+        assert!(!transfer.claim(0, 0));
+        assert!(!transfer.claim(0, 101));
+        assert!(!transfer.claim(100, 1));
+        assert!(!transfer.claim(u64::MAX, 1));
+        assert!(!transfer.claim(1, u64::MAX));
+        assert!(!transfer.claim(u64::MAX, u64::MAX));
+        assert!(transfer.ranges.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sanitize_name_accepts_plain_names() {
+        for name in [
+            "file.txt",
+            "a",
+            "with space",
+            "ünïcode",
+            "a..b",
+            &"x".repeat(255),
+        ] {
+            assert_eq!(sanitize_name(name), Some(name));
+        }
+    }
+
+    #[test]
+    fn sanitize_name_rejects_anything_else() {
+        let long = "x".repeat(256);
+        let names = [
+            "",
+            ".",
+            "..",
+            ".hidden",
+            ".file.part",
+            "a/b",
+            "/etc",
+            "a\\b",
+            "nul\0byte",
+            "new\nline",
+            &long,
+        ];
+
+        for name in names {
+            assert_eq!(sanitize_name(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn sanitize_path_stays_below_the_root() {
+        for path in ["a", "a/b/c.txt", "dir/file"] {
+            assert_eq!(sanitize_path(path), Some(path));
+        }
+
+        let paths = [
+            "",
+            "/",
+            "/etc/passwd",
+            "../escape",
+            "a/../../escape",
+            "a/./b",
+            "a//b",
+            "a/",
+            "a/.hidden",
+            "a\\..\\b",
+            "a/nul\0",
+        ];
+
+        for path in paths {
+            assert_eq!(sanitize_path(path), None, "{path:?}");
+        }
+
+        let longest = "a/".repeat(2047) + "aa";
+        assert_eq!(sanitize_path(&longest), Some(longest.as_str()));
+        assert_eq!(sanitize_path(&(longest + "a")), None);
     }
 }
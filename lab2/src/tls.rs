@@ -0,0 +1,306 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::server::WebPkiClientVerifier;
+use rustls::ClientConfig;
+use rustls::ConnectionCommon;
+use rustls::DigitallySignedStruct;
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use rustls::SideData;
+use rustls::SignatureScheme;
+use sha2::Digest;
+use sha2::Sha256;
+use socket2::Socket;
+
+use crate::to_hex;
+
+/// A socket with an optional TLS session on top. `C` is rustls'
+/// `ClientConnection` or `ServerConnection`.
+pub struct Stream<C> {
+    pub socket: Socket,
+    pub tls: Option<C>,
+}
+
+impl<C> Stream<C> {
+    pub fn new(socket: Socket) -> Self {
+        Self { socket, tls: None }
+    }
+}
+
+impl<C, S> Read for Stream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.tls {
+            Some(conn) => rustls::Stream::new(conn, &mut self.socket).read(buf),
+            None => self.socket.read(buf),
+        }
+    }
+}
+
+impl<C, S> Write for Stream<C>
+where
+    C: DerefMut<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.tls {
+            Some(conn) => rustls::Stream::new(conn, &mut self.socket).write(buf),
+            None => self.socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.tls {
+            Some(conn) => rustls::Stream::new(conn, &mut self.socket).flush(),
+            None => self.socket.flush(),
+        }
+    }
+}
+
+/// How the client decides whether to trust the server's certificate.
+pub enum Trust {
+    /// Verify the certificate chain and name against the CA certificates in
+    /// a PEM file.
+    Roots(PathBuf),
+    /// Accept exactly the certificate with this SHA-256 fingerprint.
+    Fingerprint(Vec<u8>),
+    /// Trust on first use: remember the fingerprint seen for `host` in a
+    /// known hosts file and refuse a different one later.
+    KnownHosts { path: PathBuf, host: String },
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn tls_error(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+pub fn fingerprint(cert: &CertificateDer) -> Vec<u8> {
+    Sha256::digest(cert.as_ref()).to_vec()
+}
+
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+pub fn load_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path.as_ref())?);
+
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no private key in {}", path.as_ref().display()),
+        )
+    })
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+
+    Ok(roots)
+}
+
+/// Server configuration from a PEM certificate chain and key. With
+/// `client_ca` set, clients must present a certificate signed by it.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(path)?),
+                provider(),
+            )
+            .build()
+            .map_err(io::Error::other)?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(tls_error)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Client configuration; `identity` is a certificate and key to present to
+/// servers that require client authentication.
+pub fn client_config(
+    trust: Trust,
+    identity: Option<(&Path, &Path)>,
+) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+
+    let builder =
+        match trust {
+            Trust::Roots(path) => builder.with_root_certificates(load_roots(&path)?),
+            Trust::Fingerprint(fingerprint) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pin: Pin::Fingerprint(fingerprint),
+                    provider: provider(),
+                })),
+            Trust::KnownHosts { path, host } => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pin: Pin::KnownHosts { path, host },
+                    provider: provider(),
+                })),
+        };
+
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+enum Pin {
+    Fingerprint(Vec<u8>),
+    KnownHosts { path: PathBuf, host: String },
+}
+
+/// Checks the server's certificate against a pinned fingerprint instead of a
+/// CA, so self-signed certificates can be used. Handshake signatures are
+/// still verified.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: Pin,
+    provider: Arc<CryptoProvider>,
+}
+
+/// Known hosts files have one `host fingerprint` pair per line.
+fn check_known_host(path: &Path, host: &str, presented: &[u8]) -> Result<(), rustls::Error> {
+    let presented = to_hex(presented);
+    let contents = fs::read_to_string(path).unwrap_or_default();
+
+    let known = contents.lines().find_map(|line| {
+        let (known_host, fingerprint) = line.split_once(' ')?;
+        (known_host == host).then(|| fingerprint.trim().to_string())
+    });
+
+    match known {
+        Some(known) if known == presented => Ok(()),
+
+        Some(known) => Err(rustls::Error::General(format!(
+            "certificate of {host} changed (known {known}, presented {presented}), \
+            remove it from {} if this is expected",
+            path.display()
+        ))),
+
+        None => {
+            println!("trusting new certificate of {host}: {presented}");
+
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{host} {presented}"))
+                .map_err(|err| {
+                    rustls::Error::General(format!("can't write {}: {err}", path.display()))
+                })
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = fingerprint(end_entity);
+
+        match &self.pin {
+            Pin::Fingerprint(expected) if *expected == presented => {}
+            Pin::Fingerprint(_) => {
+                return Err(rustls::Error::General(format!(
+                    "certificate fingerprint {} does not match",
+                    to_hex(&presented)
+                )))
+            }
+            Pin::KnownHosts { path, host } => check_known_host(path, host, &presented)?,
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,1434 @@
+//! The UDP transport carries the same byte stream a TCP connection would,
+//! so everything above it is unchanged: each session is handed to the
+//! client or server as one end of a local socket pair, with a thread moving
+//! the data between the other end and the network. Data goes out in
+//! numbered packets, acknowledged cumulatively and selectively; packets are
+//! resent once three sent after them are acknowledged and they're overdue
+//! by more than the jitter seen, or after a timeout. A congestion window
+//! grows as in TCP Reno to limit how many are in flight, but unlike TCP it
+//! only shrinks when losses in a round trip exceed what a lossy link drops
+//! anyway, rather than on every loss.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::SockAddr;
+use socket2::Socket;
+use socket2::Type;
+
+use crate::format_sockaddr;
+use crate::is_timeout;
+use crate::HELLO_MAGIC;
+
+/// Payload bytes per packet, small enough not to be fragmented on common
+/// paths.
+pub const MSS: usize = 1200;
+
+/// Packets a receiver buffers, and so the most a sender has in flight.
+const WINDOW: u64 = 4096;
+
+const INITIAL_RTO: Duration = Duration::from_millis(300);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(4);
+
+/// How far pacing may fall behind before it stops catching up, which
+/// limits the bursts sent to make up for it.
+const PACING_SLACK: Duration = Duration::from_millis(2);
+
+/// Socket buffer size asked for, the system may cap it.
+const SOCKET_BUFFER: usize = 4 << 20;
+
+/// Share of the packets sent in a round trip that may be lost without
+/// taking it as congestion.
+const LOSS_TOLERANCE: f64 = 0.1;
+
+/// What the congestion window shrinks to on congestion.
+const DECREASE: f64 = 0.7;
+
+/// Timeouts in a row without an acknowledgement after which the peer is
+/// given up on.
+const MAX_RETRIES: u32 = 10;
+
+/// Longest a closed session is waited for, see `Linger`.
+const LINGER: Duration = Duration::from_secs(5);
+
+/// Most selective acknowledgement ranges in one `Ack`, as many as fit in a
+/// packet no larger than a full `Data`.
+const MAX_SACKS: usize = (MSS - 16) / 16;
+
+/// A packet may be lost once this many sent after it are acknowledged, and
+/// is taken as lost once it's also overdue, see `reorder_wait`.
+const REORDER_THRESHOLD: u64 = 3;
+
+/// Most sessions the server keeps that are half-open, with nothing from
+/// the client since its `Syn`. Further `Syn`s are ignored until some of
+/// these open or time out. Half-open sessions aren't passed on to the
+/// server, so a flood of `Syn`s, spoofed or not, costs this many threads at
+/// most, though new clients are kept out while it lasts.
+const MAX_HALF_OPEN: usize = 64;
+
+/// Longest a session stays half-open before it's dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SYN: u8 = 0;
+const SYN_ACK: u8 = 1;
+const DATA: u8 = 2;
+const FIN: u8 = 3;
+const ACK: u8 = 4;
+const RESET: u8 = 5;
+
+/// Packets start with their kind and the ID the client picked for the
+/// session, which keeps apart sessions from the same address, such as a
+/// client behind NAT reconnecting before its old session timed out. `Data`
+/// and `Fin` take up a sequence number each, and `Ack` acknowledges
+/// everything before `next` plus the `sacks` ranges, while announcing how
+/// many packets past `next` the receiver has room for.
+#[derive(Clone)]
+enum Packet {
+    Syn,
+    SynAck,
+    Data {
+        seq: u64,
+        payload: Vec<u8>,
+    },
+    Fin {
+        seq: u64,
+    },
+    Ack {
+        next: u64,
+        window: u64,
+        sacks: Vec<(u64, u64)>,
+    },
+    Reset,
+}
+
+impl Packet {
+    fn encode(&self, id: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MSS + 17);
+
+        bytes.push(self.kind());
+        bytes.extend_from_slice(&id.to_be_bytes());
+
+        match self {
+            Packet::Syn | Packet::SynAck => bytes.extend_from_slice(&HELLO_MAGIC),
+            Packet::Data { seq, payload } => {
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(payload);
+            }
+            Packet::Fin { seq } => bytes.extend_from_slice(&seq.to_be_bytes()),
+            Packet::Ack {
+                next,
+                window,
+                sacks,
+            } => {
+                bytes.extend_from_slice(&next.to_be_bytes());
+                bytes.extend_from_slice(&window.to_be_bytes());
+
+                for (start, end) in sacks {
+                    bytes.extend_from_slice(&start.to_be_bytes());
+                    bytes.extend_from_slice(&end.to_be_bytes());
+                }
+            }
+            Packet::Reset => {}
+        }
+
+        bytes
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Packet::Syn => SYN,
+            Packet::SynAck => SYN_ACK,
+            Packet::Data { .. } => DATA,
+            Packet::Fin { .. } => FIN,
+            Packet::Ack { .. } => ACK,
+            Packet::Reset => RESET,
+        }
+    }
+
+    /// Returns the packet and the ID of its session.
+    fn decode(bytes: &[u8]) -> Option<(u64, Self)> {
+        let (&kind, rest) = bytes.split_first()?;
+        let id = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+        let rest = &rest[8..];
+        let u64_at = |at: usize| -> Option<u64> {
+            Some(u64::from_be_bytes(rest.get(at..at + 8)?.try_into().ok()?))
+        };
+
+        let packet = match kind {
+            SYN if rest == HELLO_MAGIC => Some(Packet::Syn),
+            SYN_ACK if rest == HELLO_MAGIC => Some(Packet::SynAck),
+            DATA if rest.len() > 8 => Some(Packet::Data {
+                seq: u64_at(0)?,
+                payload: rest[8..].to_vec(),
+            }),
+            FIN if rest.len() == 8 => Some(Packet::Fin { seq: u64_at(0)? }),
+            ACK if rest.len() >= 16 && (rest.len() - 16) % 16 == 0 => Some(Packet::Ack {
+                next: u64_at(0)?,
+                window: u64_at(8)?,
+                sacks: (16..rest.len())
+                    .step_by(16)
+                    .map(|at| Some((u64_at(at)?, u64_at(at + 8)?)))
+                    .collect::<Option<_>>()?,
+            }),
+            RESET if rest.is_empty() => Some(Packet::Reset),
+            _ => None,
+        }?;
+
+        Some((id, packet))
+    }
+}
+
+/// Packets for one session, passed from the thread reading the UDP socket
+/// to the one driving the session, which `wake` tells about them.
+struct Inbox {
+    packets: Mutex<VecDeque<Packet>>,
+    wake: UnixDatagram,
+    /// Set on sessions the server accepted until the client sends anything
+    /// but a `Syn` on them.
+    half_open: AtomicBool,
+    /// The socket of a half-open session, for the server once it opens.
+    pending: Mutex<Option<Socket>>,
+}
+
+impl Inbox {
+    fn push(&self, packet: Packet) {
+        let mut packets = self.packets.lock().unwrap();
+
+        // like a full socket buffer, the sender will retransmit
+        if packets.len() as u64 >= 2 * WINDOW {
+            return;
+        }
+
+        packets.push_back(packet);
+
+        if packets.len() == 1 {
+            let _ = self.wake.send(&[0]);
+        }
+    }
+}
+
+/// A UDP socket and the sessions going through it, by peer and session ID.
+struct Shared {
+    socket: UdpSocket,
+    sessions: Mutex<HashMap<(SocketAddr, u64), Arc<Inbox>>>,
+    /// How many of them are half-open.
+    half_open: AtomicUsize,
+}
+
+impl Shared {
+    fn send(&self, packet: &Packet, peer: SocketAddr, id: u64) {
+        // lost like any other packet if this fails
+        let _ = self.socket.send_to(&packet.encode(id), peer);
+    }
+
+    /// Counts the session of `inbox` as open, if it wasn't already, and
+    /// returns its socket if it was held back until then.
+    fn opened(&self, inbox: &Inbox) -> Option<Socket> {
+        if !inbox.half_open.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+
+        self.half_open.fetch_sub(1, Ordering::Relaxed);
+        inbox.pending.lock().unwrap().take()
+    }
+
+    /// Reads packets off the socket and hands them to their sessions. With
+    /// `incoming` set, a `Syn` for a new session starts it, unless too many
+    /// are half-open, and it's passed on there once the client follows up;
+    /// without, the loop ends along with the last session.
+    fn receive(self: Arc<Self>, incoming: Option<mpsc::Sender<(Socket, SocketAddr)>>) {
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            let received = self.socket.recv_from(&mut buffer);
+
+            if incoming.is_none() && self.sessions.lock().unwrap().is_empty() {
+                return;
+            }
+
+            let Ok((len, peer)) = received else {
+                continue;
+            };
+
+            let Some((id, packet)) = Packet::decode(&buffer[..len]) else {
+                continue;
+            };
+
+            let inbox = self.sessions.lock().unwrap().get(&(peer, id)).cloned();
+
+            match (inbox, packet, &incoming) {
+                // our answer was lost
+                (Some(_), Packet::Syn, _) => self.send(&Packet::SynAck, peer, id),
+                (Some(inbox), Packet::Reset, _) => inbox.push(Packet::Reset),
+                (Some(inbox), packet, _) => {
+                    if let (Some(socket), Some(incoming)) = (self.opened(&inbox), &incoming) {
+                        if incoming.send((socket, peer)).is_err() {
+                            return;
+                        }
+                    }
+
+                    inbox.push(packet);
+                }
+                // the client tries again later
+                (None, Packet::Syn, Some(_))
+                    if self.half_open.load(Ordering::Relaxed) >= MAX_HALF_OPEN => {}
+                (None, Packet::Syn, Some(_)) => match self.start(peer, id, true) {
+                    Ok((socket, _)) => {
+                        self.send(&Packet::SynAck, peer, id);
+
+                        // ended already if it's gone, see `opened`
+                        if let Some(inbox) = self.sessions.lock().unwrap().get(&(peer, id)) {
+                            *inbox.pending.lock().unwrap() = Some(socket);
+                        }
+                    }
+                    Err(err) => eprintln!("error starting udp session: {err}"),
+                },
+                (None, Packet::Reset, _) => {}
+                // a session that's over, or never was
+                (None, _, _) => self.send(&Packet::Reset, peer, id),
+            }
+        }
+    }
+
+    /// Starts session `id` with `peer` and returns the socket its data goes
+    /// through, and a channel that disconnects when the session is over.
+    /// With `accepted` set, the peer opened it: it's half-open until the
+    /// peer follows up, and a summary is printed when it ends.
+    fn start(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        id: u64,
+        accepted: bool,
+    ) -> io::Result<(Socket, mpsc::Receiver<()>)> {
+        let (app, end) = UnixStream::pair()?;
+        let (wake, woken) = UnixDatagram::pair()?;
+
+        end.set_nonblocking(true)?;
+        wake.set_nonblocking(true)?;
+        woken.set_nonblocking(true)?;
+
+        let inbox = Arc::new(Inbox {
+            packets: Mutex::new(VecDeque::new()),
+            wake,
+            half_open: AtomicBool::new(accepted),
+            pending: Mutex::new(None),
+        });
+
+        if accepted {
+            self.half_open.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert((peer, id), inbox.clone());
+
+        let shared = self.clone();
+        let (sender, over) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            // dropped when the thread ends
+            let _sender = sender;
+            let mut session = Session::new(shared.clone(), peer, id, inbox, woken, end);
+            let result = session.run();
+
+            shared.sessions.lock().unwrap().remove(&(peer, id));
+            // a socket still held back goes with the session
+            shared.opened(&session.inbox);
+
+            if accepted {
+                session.log(result);
+            }
+        });
+
+        Ok((Socket::from(OwnedFd::from(app)), over))
+    }
+}
+
+/// Waits, when dropped, for the session to be closed with the peer after
+/// its socket was, so that a process exiting after its last upload doesn't
+/// leave the server waiting for an end that never comes.
+pub struct Linger(mpsc::Receiver<()>);
+
+impl Drop for Linger {
+    fn drop(&mut self) {
+        let _ = self.0.recv_timeout(LINGER);
+    }
+}
+
+fn session_id() -> io::Result<u64> {
+    let mut id = [0u8; 8];
+
+    rustls::crypto::ring::default_provider()
+        .secure_random
+        .fill(&mut id)
+        .map_err(|_| io::Error::other("can't generate a session id"))?;
+
+    Ok(u64::from_be_bytes(id))
+}
+
+fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.set_recv_buffer_size(SOCKET_BUFFER)?;
+    socket.set_send_buffer_size(SOCKET_BUFFER)?;
+    socket.bind(&SockAddr::from(addr))?;
+
+    Ok(socket.into())
+}
+
+/// The server end of the UDP transport: one socket all client sessions go
+/// through.
+pub struct Endpoint {
+    shared: Arc<Shared>,
+    incoming: Mutex<mpsc::Receiver<(Socket, SocketAddr)>>,
+}
+
+impl Endpoint {
+    /// Listens on `addr`, dual-stack when it's the IPv6 unspecified address.
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            socket: bind(addr)?,
+            sessions: Mutex::new(HashMap::new()),
+            half_open: AtomicUsize::new(0),
+        });
+
+        let (sender, incoming) = mpsc::channel();
+        let receiving = shared.clone();
+
+        thread::spawn(move || receiving.receive(Some(sender)));
+
+        Ok(Self {
+            shared,
+            incoming: Mutex::new(incoming),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Waits for a client to open a session. Returns the socket to serve it
+    /// on, as if it were a TCP connection, and the client's address.
+    pub fn accept(&self) -> io::Result<(Socket, SocketAddr)> {
+        self.incoming
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "udp endpoint closed"))
+    }
+}
+
+/// Opens a session with the server at `addr`, waiting at most `timeout` for
+/// it to answer. Returns the socket to use as if it were a TCP connection,
+/// and a `Linger` to drop after it.
+pub fn connect(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<(Socket, Linger)> {
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let socket = bind(local)?;
+    let id = session_id()?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut rto = INITIAL_RTO;
+    let mut buffer = [0u8; 64];
+
+    for _ in 0..=MAX_RETRIES {
+        let wait = match deadline {
+            Some(deadline) => rto.min(deadline.saturating_duration_since(Instant::now())),
+            None => rto,
+        };
+
+        if wait.is_zero() {
+            break;
+        }
+
+        socket.send_to(&Packet::Syn.encode(id), addr)?;
+        socket.set_read_timeout(Some(wait))?;
+
+        match socket.recv_from(&mut buffer) {
+            Ok((len, peer)) if peer == addr => {
+                let answer = Packet::decode(&buffer[..len]);
+
+                // meant for some other session
+                if !matches!(answer, Some((answered, Packet::SynAck)) if answered == id) {
+                    continue;
+                }
+
+                // lets the receiving thread notice the session ended
+                socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+                let shared = Arc::new(Shared {
+                    socket,
+                    sessions: Mutex::new(HashMap::new()),
+                    half_open: AtomicUsize::new(0),
+                });
+
+                let (app, over) = shared.start(addr, id, false)?;
+                thread::spawn(move || shared.receive(None));
+
+                return Ok((app, Linger(over)));
+            }
+            Ok(_) => {}
+            Err(err) if is_timeout(&err) => rto = (rto * 2).min(MAX_RTO),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no answer from the server over udp",
+    ))
+}
+
+/// A packet waiting to be acknowledged. `tx` counts transmissions over the
+/// session, so that a retransmission counts as sent after everything before
+/// it.
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    tx: u64,
+    retransmitted: bool,
+    lost: bool,
+}
+
+/// Why a session ended early.
+enum Abort {
+    Reset,
+    Unreachable,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Abort {
+    fn from(err: io::Error) -> Self {
+        Abort::Io(err)
+    }
+}
+
+/// Moves the data of one session between the local socket and the peer.
+struct Session {
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    id: u64,
+    inbox: Arc<Inbox>,
+    woken: UnixDatagram,
+    app: UnixStream,
+
+    next_seq: u64,
+    next_tx: u64,
+    unacked: BTreeMap<u64, Sent>,
+    in_flight: u64,
+    app_done: bool,
+    cwnd: f64,
+    ssthresh: f64,
+    /// A round trip ends once the peer acknowledges up to here, and
+    /// `round_lost` of the `round_sent` packets sent in it were lost.
+    round_end: u64,
+    round_sent: u64,
+    round_lost: u64,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    retries: u32,
+    /// Transmission count of the last packet sent of those acknowledged.
+    newest_acked: u64,
+    /// Restarted whenever the peer acknowledges something new, see RFC 6298.
+    rto_start: Instant,
+    /// When the first packet overtaken by others becomes overdue.
+    loss_timer: Option<Instant>,
+    peer_next: u64,
+    peer_window: u64,
+    last_send: Instant,
+    /// Earliest the next packet goes out, spreading each window over a
+    /// round trip rather than sending it in one burst that overflows
+    /// buffers along the way.
+    next_send: Instant,
+
+    expected: u64,
+    received: BTreeMap<u64, Packet>,
+    deliver: VecDeque<u8>,
+    peer_done: bool,
+    app_closed: bool,
+    ack_due: bool,
+    /// One past the last packet the peer was last told there's room for.
+    edge: u64,
+
+    sent: u64,
+    retransmitted: u64,
+    arrived: u64,
+    duplicates: u64,
+    started: Instant,
+}
+
+impl Session {
+    fn new(
+        shared: Arc<Shared>,
+        peer: SocketAddr,
+        id: u64,
+        inbox: Arc<Inbox>,
+        woken: UnixDatagram,
+        app: UnixStream,
+    ) -> Self {
+        Self {
+            shared,
+            peer,
+            id,
+            inbox,
+            woken,
+            app,
+            next_seq: 0,
+            next_tx: 0,
+            unacked: BTreeMap::new(),
+            in_flight: 0,
+            app_done: false,
+            cwnd: 10.,
+            ssthresh: WINDOW as f64,
+            round_end: 0,
+            round_sent: 0,
+            round_lost: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            retries: 0,
+            newest_acked: 0,
+            rto_start: Instant::now(),
+            loss_timer: None,
+            peer_next: 0,
+            peer_window: WINDOW,
+            last_send: Instant::now(),
+            next_send: Instant::now(),
+            expected: 0,
+            received: BTreeMap::new(),
+            deliver: VecDeque::new(),
+            peer_done: false,
+            app_closed: false,
+            ack_due: false,
+            edge: WINDOW,
+            sent: 0,
+            retransmitted: 0,
+            arrived: 0,
+            duplicates: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), Abort> {
+        let result = self.drive();
+
+        match &result {
+            Err(Abort::Reset) => {}
+            Err(_) => self.shared.send(&Packet::Reset, self.peer, self.id),
+            Ok(_) => {}
+        }
+
+        let _ = self.app.shutdown(Shutdown::Both);
+
+        if let Ok(true) = result {
+            self.linger();
+        }
+
+        result.map(|_| ())
+    }
+
+    /// Moves data until both ends are done. Returns whether an ack went out
+    /// last, which the peer may still be waiting for.
+    fn drive(&mut self) -> Result<bool, Abort> {
+        loop {
+            self.wait()?;
+
+            let _ = self.woken.recv(&mut [0u8; 64]);
+            let packets = std::mem::take(&mut *self.inbox.packets.lock().unwrap());
+
+            for packet in packets {
+                self.handle(packet)?;
+            }
+
+            if self.half_open() && self.started.elapsed() >= HANDSHAKE_TIMEOUT {
+                return Err(Abort::Unreachable);
+            }
+
+            self.deliver()?;
+            self.check_losses();
+            self.check_timeout()?;
+            self.send_lost();
+            self.send_new()?;
+
+            let acked = self.ack_due;
+
+            if self.ack_due {
+                self.send_ack();
+            }
+
+            if self.app_done && self.unacked.is_empty() && self.peer_done && self.deliver.is_empty()
+            {
+                return Ok(acked);
+            }
+        }
+    }
+
+    /// Stays around for two timeouts after the session is over, answering
+    /// the peer's `Fin` or data again should the last ack have been lost,
+    /// where a session that's gone would answer with a `Reset`.
+    fn linger(&mut self) {
+        let until = Instant::now() + 2 * self.rto;
+        let _ = self.woken.set_nonblocking(false);
+
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+
+            if left.is_zero() {
+                return;
+            }
+
+            let _ = self.woken.set_read_timeout(Some(left));
+            let _ = self.woken.recv(&mut [0u8; 64]);
+            let packets = std::mem::take(&mut *self.inbox.packets.lock().unwrap());
+
+            for packet in packets {
+                match packet {
+                    Packet::Data { .. } | Packet::Fin { .. } => self.send_ack(),
+                    Packet::Reset => return,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Sleeps until there's a packet, something to do with the local
+    /// socket, or a retransmission due.
+    fn wait(&self) -> io::Result<()> {
+        let mut app_events = 0;
+
+        if self.can_send() && !self.app_done {
+            app_events |= libc::POLLIN;
+        }
+
+        if !self.deliver.is_empty() {
+            app_events |= libc::POLLOUT;
+        }
+
+        let mut fds = [
+            libc::pollfd {
+                fd: self.woken.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                // a hung up socket would wake poll up for nothing
+                fd: if app_events == 0 {
+                    -1
+                } else {
+                    self.app.as_raw_fd()
+                },
+                events: app_events,
+                revents: 0,
+            },
+        ];
+
+        let paced = Some(self.next_send).filter(|_| self.held_by_pacing());
+        let handshake = Some(self.started + HANDSHAKE_TIMEOUT).filter(|_| self.half_open());
+        let wakeups = [self.deadline(), self.loss_timer, paced, handshake];
+
+        let timeout = match wakeups.into_iter().flatten().min() {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+
+        // SAFETY: `fds` is valid for its length.
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+
+        if result < 0 {
+            let err = io::Error::last_os_error();
+
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// When the packets in flight time out, or a stalled sender probes the
+    /// receiver's window.
+    fn deadline(&self) -> Option<Instant> {
+        if self.in_flight > 0 {
+            Some(self.rto_start + self.rto)
+        } else if self.unacked.is_empty() && !self.app_done && !self.within_window() {
+            Some(self.last_send + self.rto)
+        } else {
+            None
+        }
+    }
+
+    fn half_open(&self) -> bool {
+        self.inbox.half_open.load(Ordering::Relaxed)
+    }
+
+    fn within_window(&self) -> bool {
+        self.next_seq < self.peer_next + self.peer_window
+    }
+
+    fn can_send(&self) -> bool {
+        (self.in_flight as f64) < self.cwnd && self.within_window() && !self.paced()
+    }
+
+    fn paced(&self) -> bool {
+        Instant::now() < self.next_send
+    }
+
+    /// Whether there may be something to send once pacing allows it.
+    fn held_by_pacing(&self) -> bool {
+        let room = (self.in_flight as f64) < self.cwnd;
+        let lost = self.unacked.len() as u64 > self.in_flight;
+
+        self.paced() && room && (lost || (!self.app_done && self.within_window()))
+    }
+
+    /// Moves `next_send` on by a packet's share of the round trip, sending
+    /// faster than the window strictly allows so as to still grow it.
+    /// Without an estimate of the round trip yet, packets aren't paced.
+    fn pace(&mut self) {
+        let Some(srtt) = self.srtt else {
+            return;
+        };
+
+        let gain = if self.cwnd < self.ssthresh { 2. } else { 1.25 };
+        let now = Instant::now();
+        // poll sleeps whole milliseconds, catch up after oversleeping
+        let earliest = now.checked_sub(PACING_SLACK).unwrap_or(now);
+
+        self.next_send = self.next_send.max(earliest) + srtt.div_f64(self.cwnd * gain);
+    }
+
+    fn handle(&mut self, packet: Packet) -> Result<(), Abort> {
+        match packet {
+            Packet::Data { seq, .. } | Packet::Fin { seq } => {
+                self.ack_due = true;
+                self.arrived += 1;
+
+                if seq < self.expected || self.received.contains_key(&seq) {
+                    self.duplicates += 1;
+                } else if seq < self.expected + 2 * WINDOW && self.buffered() < 2 * WINDOW {
+                    // beyond that, what a peer ignoring the window sends is
+                    // dropped as if lost
+                    self.received.insert(seq, packet);
+                }
+
+                while let Some(packet) = self.received.remove(&self.expected) {
+                    match packet {
+                        Packet::Data { payload, .. } if !self.app_closed => {
+                            self.deliver.extend(payload)
+                        }
+                        Packet::Fin { .. } => self.peer_done = true,
+                        _ => {}
+                    }
+
+                    self.expected += 1;
+                }
+            }
+            Packet::Ack {
+                next,
+                window,
+                sacks,
+            } => self.on_ack(next, window, &sacks),
+            Packet::Reset => return Err(Abort::Reset),
+            Packet::Syn | Packet::SynAck => {}
+        }
+
+        Ok(())
+    }
+
+    fn on_ack(&mut self, next: u64, window: u64, sacks: &[(u64, u64)]) {
+        if next >= self.peer_next {
+            self.peer_next = next;
+            self.peer_window = window;
+        }
+
+        let mut acked: Vec<u64> = self.unacked.range(..next).map(|(seq, _)| *seq).collect();
+
+        for (start, end) in sacks {
+            if start < end {
+                acked.extend(self.unacked.range(start..end).map(|(seq, _)| *seq));
+            }
+        }
+
+        let now = Instant::now();
+        let mut newest_tx = None;
+        let mut sample = None;
+
+        for seq in acked {
+            let Some(sent) = self.unacked.remove(&seq) else {
+                continue;
+            };
+
+            if !sent.lost {
+                self.in_flight -= 1;
+            }
+
+            // only unambiguous samples, see Karn's algorithm
+            if !sent.retransmitted {
+                sample = Some(now - sent.sent_at);
+            }
+
+            newest_tx = newest_tx.max(Some(sent.tx));
+
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1.;
+            } else {
+                self.cwnd += 1. / self.cwnd;
+            }
+        }
+
+        let Some(newest_tx) = newest_tx else {
+            return;
+        };
+
+        self.retries = 0;
+        self.rto_start = now;
+        self.newest_acked = self.newest_acked.max(newest_tx);
+
+        if let Some(sample) = sample {
+            self.update_rtt(sample);
+        }
+    }
+
+    /// How long after being sent a packet that others sent after it
+    /// overtook is taken as lost rather than delayed.
+    fn reorder_wait(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => srtt + (srtt / 4).max(self.rttvar * 2),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// Marks overdue packets as lost, and at the end of each round trip
+    /// shrinks the congestion window if too many were.
+    fn check_losses(&mut self) {
+        let now = Instant::now();
+        let wait = self.reorder_wait();
+        let mut lost = 0;
+
+        self.loss_timer = None;
+
+        for sent in self.unacked.values_mut() {
+            let overtaken = sent.tx + REORDER_THRESHOLD <= self.newest_acked;
+
+            // packets after one sent only once were all sent after it too
+            if !overtaken && !sent.retransmitted {
+                break;
+            }
+
+            if !overtaken || sent.lost {
+                continue;
+            }
+
+            if now >= sent.sent_at + wait {
+                sent.lost = true;
+                lost += 1;
+            } else {
+                let due = sent.sent_at + wait;
+                self.loss_timer = Some(self.loss_timer.map_or(due, |timer| timer.min(due)));
+            }
+        }
+
+        self.in_flight -= lost;
+        self.round_lost += lost;
+
+        if self.peer_next >= self.round_end {
+            let congested = self.round_lost as f64 > self.round_sent as f64 * LOSS_TOLERANCE;
+
+            if congested {
+                self.ssthresh = (self.cwnd * DECREASE).max(2.);
+                self.cwnd = self.ssthresh;
+            }
+
+            self.round_end = self.next_seq;
+            self.round_sent = 0;
+            self.round_lost = 0;
+        }
+    }
+
+    /// RFC 6298.
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(sample)) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+
+        self.rto = (self.srtt.unwrap_or_default() + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Takes everything in flight as lost once the oldest packet has gone
+    /// unacknowledged for the retransmission timeout, backing off each time.
+    fn check_timeout(&mut self) -> Result<(), Abort> {
+        let Some(deadline) = self.deadline() else {
+            return Ok(());
+        };
+
+        if Instant::now() < deadline {
+            return Ok(());
+        }
+
+        self.retries += 1;
+
+        if self.retries > MAX_RETRIES {
+            return Err(Abort::Unreachable);
+        }
+
+        for sent in self.unacked.values_mut() {
+            if !sent.lost {
+                sent.lost = true;
+                self.in_flight -= 1;
+            }
+        }
+
+        self.ssthresh = (self.cwnd * DECREASE).max(2.);
+        self.cwnd = 1.;
+        self.round_end = self.next_seq;
+        self.round_sent = 0;
+        self.round_lost = 0;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+
+        // a probe past a window that may have reopened without us hearing
+        if self.unacked.is_empty() && !self.app_done {
+            self.peer_window = self.peer_window.max(self.next_seq + 1 - self.peer_next);
+        }
+
+        self.last_send = Instant::now();
+
+        Ok(())
+    }
+
+    fn transmit(&mut self, packet: &Packet) {
+        if self.in_flight == 0 {
+            self.rto_start = Instant::now();
+        }
+
+        self.shared.send(packet, self.peer, self.id);
+        self.sent += 1;
+        self.next_tx += 1;
+        self.round_sent += 1;
+        self.last_send = Instant::now();
+        self.pace();
+    }
+
+    fn send_lost(&mut self) {
+        if self.unacked.len() as u64 == self.in_flight {
+            return;
+        }
+
+        let lost: Vec<u64> = self
+            .unacked
+            .iter()
+            .filter(|(_, sent)| sent.lost)
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        for seq in lost {
+            if self.in_flight as f64 >= self.cwnd || self.paced() {
+                break;
+            }
+
+            let packet = self.unacked[&seq].packet.clone();
+            let tx = self.next_tx;
+            self.transmit(&packet);
+            self.retransmitted += 1;
+            self.in_flight += 1;
+
+            let sent = self.unacked.get_mut(&seq).unwrap();
+            sent.lost = false;
+            sent.retransmitted = true;
+            sent.sent_at = Instant::now();
+            sent.tx = tx;
+        }
+    }
+
+    /// Sends what the local socket has to send, as far as the windows allow.
+    fn send_new(&mut self) -> Result<(), Abort> {
+        let mut buffer = [0u8; MSS];
+
+        while !self.app_done && self.can_send() {
+            let packet = match self.app.read(&mut buffer) {
+                Ok(0) => {
+                    self.app_done = true;
+                    Packet::Fin { seq: self.next_seq }
+                }
+                Ok(len) => Packet::Data {
+                    seq: self.next_seq,
+                    payload: buffer[..len].to_vec(),
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            let tx = self.next_tx;
+            self.transmit(&packet);
+            self.in_flight += 1;
+
+            self.unacked.insert(
+                self.next_seq,
+                Sent {
+                    packet,
+                    sent_at: Instant::now(),
+                    tx,
+                    retransmitted: false,
+                    lost: false,
+                },
+            );
+
+            self.next_seq += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the data received in order to the local socket, which reads
+    /// end of file once the peer is done.
+    fn deliver(&mut self) -> io::Result<()> {
+        while !self.deliver.is_empty() {
+            let (data, _) = self.deliver.as_slices();
+
+            match self.app.write(data) {
+                Ok(written) => {
+                    self.deliver.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // nobody to read it any more, as TCP drops it
+                Err(_) => {
+                    self.app_closed = true;
+                    self.deliver.clear();
+                }
+            }
+        }
+
+        if self.peer_done && self.deliver.is_empty() {
+            let _ = self.app.shutdown(Shutdown::Write);
+        }
+
+        // tell a sender that may be held back by the window there's room
+        // again, without an ack for every packet read
+        if self.expected + self.window() >= self.edge + WINDOW / 4 {
+            self.ack_due = true;
+        }
+
+        Ok(())
+    }
+
+    /// Packets held, out of order or waiting for the local socket.
+    fn buffered(&self) -> u64 {
+        self.received.len() as u64 + (self.deliver.len() / MSS) as u64
+    }
+
+    fn window(&self) -> u64 {
+        WINDOW.saturating_sub(self.buffered())
+    }
+
+    fn send_ack(&mut self) {
+        let mut sacks = Vec::new();
+
+        for seq in self.received.keys() {
+            let full = sacks.len() == MAX_SACKS;
+
+            match sacks.last_mut() {
+                Some((_, end)) if *end == *seq => *end += 1,
+                _ if full => break,
+                _ => sacks.push((*seq, *seq + 1)),
+            }
+        }
+
+        let window = self.window();
+        self.edge = self.expected + window;
+
+        self.shared.send(
+            &Packet::Ack {
+                next: self.expected,
+                window,
+                sacks,
+            },
+            self.peer,
+            self.id,
+        );
+
+        self.ack_due = false;
+    }
+
+    fn log(&self, result: Result<(), Abort>) {
+        let addr = format_sockaddr(&SockAddr::from(self.peer));
+
+        match result {
+            Ok(()) => {}
+            Err(Abort::Reset) => println!("{addr}: udp session reset by peer"),
+            Err(Abort::Unreachable) => println!("{addr}: udp session timed out"),
+            Err(Abort::Io(err)) => println!("{addr}: udp session failed: {err}"),
+        }
+
+        println!(
+            "{}: udp session over {:.1}s, {} packets sent, {} retransmitted, {} received, {} duplicate, srtt {:.1}ms",
+            addr,
+            self.started.elapsed().as_secs_f64(),
+            self.sent,
+            self.retransmitted,
+            self.arrived,
+            self.duplicates,
+            self.srtt.unwrap_or_default().as_secs_f64() * 1000.
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn packets() -> Vec<Packet> {
+        vec![
+            Packet::Syn,
+            Packet::SynAck,
+            Packet::Data {
+                seq: 42,
+                payload: vec![1, 2, 3],
+            },
+            Packet::Data {
+                seq: u64::MAX,
+                payload: vec![0xff; MSS],
+            },
+            Packet::Fin { seq: 7 },
+            Packet::Ack {
+                next: 3,
+                window: WINDOW,
+                sacks: vec![],
+            },
+            Packet::Ack {
+                next: 3,
+                window: 0,
+                sacks: vec![(5, 6), (8, 12)],
+            },
+            Packet::Reset,
+        ]
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        for packet in packets() {
+            let bytes = packet.encode(0x0123_4567_89ab_cdef);
+            let (id, decoded) = Packet::decode(&bytes).unwrap();
+
+            assert_eq!(id, 0x0123_4567_89ab_cdef);
+            assert_eq!(decoded.encode(id), bytes);
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_dropped() {
+        for packet in packets() {
+            let bytes = packet.encode(1);
+
+            for len in 0..bytes.len() {
+                let prefix = &bytes[..len];
+
+                // shorter data, or fewer ranges, is still a valid packet
+                let valid = match packet {
+                    Packet::Data { .. } => len > 1 + 8 + 8,
+                    Packet::Ack { .. } => len >= 1 + 8 + 16 && (len - 1 - 8) % 16 == 0,
+                    _ => false,
+                };
+
+                assert_eq!(Packet::decode(prefix).is_some(), valid, "{prefix:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_packets_are_dropped() {
+        let mut wrong_magic = Packet::Syn.encode(1);
+        *wrong_magic.last_mut().unwrap() ^= 1;
+
+        let mut unknown = Packet::Reset.encode(1);
+        unknown[0] = RESET + 1;
+
+        let mut trailing = Packet::Fin { seq: 1 }.encode(1);
+        trailing.push(0);
+
+        for bytes in [wrong_magic, unknown, trailing] {
+            assert!(Packet::decode(&bytes).is_none(), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn acks_fit_in_a_data_packet() {
+        let ack = Packet::Ack {
+            next: 0,
+            window: 0,
+            sacks: vec![(0, 0); MAX_SACKS],
+        };
+        let data = Packet::Data {
+            seq: 0,
+            payload: vec![0; MSS],
+        };
+
+        assert!(ack.encode(0).len() <= data.encode(0).len());
+    }
+
+    /// A session sending to `peer`, and the other end of its local socket.
+    fn session() -> (Session, UnixStream, UdpSocket) {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let shared = Arc::new(Shared {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            sessions: Mutex::new(HashMap::new()),
+            half_open: AtomicUsize::new(0),
+        });
+
+        let (app, end) = UnixStream::pair().unwrap();
+        let (wake, woken) = UnixDatagram::pair().unwrap();
+        end.set_nonblocking(true).unwrap();
+
+        let inbox = Arc::new(Inbox {
+            packets: Mutex::new(VecDeque::new()),
+            wake,
+            half_open: AtomicBool::new(false),
+            pending: Mutex::new(None),
+        });
+
+        let addr = peer.local_addr().unwrap();
+        let session = Session::new(shared, addr, 9, inbox, woken, end);
+
+        (session, app, peer)
+    }
+
+    fn receive(peer: &UdpSocket) -> Packet {
+        let mut buffer = [0u8; 2 * MSS];
+        let (len, _) = peer.recv_from(&mut buffer).unwrap();
+        let (id, packet) = Packet::decode(&buffer[..len]).unwrap();
+
+        assert_eq!(id, 9);
+        packet
+    }
+
+    fn data(seq: u64) -> Packet {
+        Packet::Data {
+            seq,
+            payload: vec![seq as u8; 10],
+        }
+    }
+
+    #[test]
+    fn acks_cover_what_arrived_out_of_order() {
+        let (mut session, _app, peer) = session();
+
+        for seq in [0, 2, 3, 5, 3] {
+            session.handle(data(seq)).ok().unwrap();
+        }
+
+        session.send_ack();
+
+        let Packet::Ack { next, sacks, .. } = receive(&peer) else {
+            panic!("expected an ack");
+        };
+
+        assert_eq!(next, 1);
+        assert_eq!(sacks, [(2, 4), (5, 6)]);
+        assert_eq!(session.deliver, [0; 10]);
+        assert_eq!(session.duplicates, 1);
+
+        session.handle(data(1)).ok().unwrap();
+        session.send_ack();
+
+        let Packet::Ack { next, sacks, .. } = receive(&peer) else {
+            panic!("expected an ack");
+        };
+
+        assert_eq!(next, 4);
+        assert_eq!(sacks, [(5, 6)]);
+        assert_eq!(session.deliver.len(), 40);
+    }
+
+    #[test]
+    fn overtaken_packets_are_resent() {
+        let (mut session, mut app, peer) = session();
+
+        app.write_all(&vec![0; 6 * MSS]).unwrap();
+        session.send_new().ok().unwrap();
+        assert_eq!(session.next_seq, 6);
+
+        for _ in 0..6 {
+            receive(&peer);
+        }
+
+        // two sent after it arriving isn't enough
+        session.on_ack(0, WINDOW, &[(1, 3)]);
+        thread::sleep(Duration::from_millis(5));
+        session.check_losses();
+        assert!(!session.unacked[&0].lost);
+
+        session.on_ack(0, WINDOW, &[(3, 5)]);
+        thread::sleep(session.reorder_wait());
+        session.check_losses();
+        assert!(session.unacked[&0].lost);
+        assert!(!session.unacked[&5].lost);
+        assert_eq!(session.in_flight, 1);
+
+        session.send_lost();
+        assert!(matches!(receive(&peer), Packet::Data { seq: 0, .. }));
+        assert!(session.unacked[&0].retransmitted);
+        assert_eq!(session.retransmitted, 1);
+    }
+
+    #[test]
+    fn a_peer_ignoring_the_window_is_held_to_the_buffer() {
+        let (mut session, _app, _peer) = session();
+
+        for seq in 0..3 * WINDOW {
+            let packet = Packet::Data {
+                seq,
+                payload: vec![0; MSS],
+            };
+
+            session.handle(packet).ok().unwrap();
+        }
+
+        assert_eq!(session.buffered(), 2 * WINDOW);
+        assert_eq!(session.window(), 0);
+    }
+}
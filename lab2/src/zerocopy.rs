@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io;
+
+use socket2::Socket;
+
+/// Most bytes moved per call, so that progress and rate limits still get a
+/// say in between.
+pub const CHUNK: usize = 1024 * 1024;
+
+/// Whether `err` from `sendfile` or `splice` means they can't be used with
+/// these descriptors and the buffered copy should be used instead.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::Unsupported
+        || matches!(
+            err.raw_os_error(),
+            Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+        )
+}
+
+/// Sends up to `len` bytes of `file` at `offset` to `socket` without
+/// copying them through user space. Doesn't move the file position.
+#[cfg(target_os = "linux")]
+pub fn sendfile(socket: &Socket, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+
+    // SAFETY: both descriptors are open for the duration of the call and
+    // `offset` outlives it.
+    let sent = unsafe {
+        libc::sendfile(
+            socket.as_raw_fd(),
+            file.as_raw_fd(),
+            &mut offset,
+            len.min(CHUNK),
+        )
+    };
+
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(sent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sendfile(_socket: &Socket, _file: &File, _offset: u64, _len: usize) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Moves data from a socket into a file with `splice`, which needs a pipe
+/// on one side of every move.
+#[cfg(target_os = "linux")]
+pub struct Splice {
+    read: std::os::fd::OwnedFd,
+    write: std::os::fd::OwnedFd,
+    usable: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Splice {
+    pub fn new() -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+        use std::os::fd::FromRawFd;
+        use std::os::fd::OwnedFd;
+
+        let mut fds = [0; 2];
+
+        // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: pipe2 succeeded, so both are open and owned by nobody else.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // a bigger pipe means fewer calls; the default of 64 KiB still works
+        // SAFETY: `write` is an open pipe.
+        unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, CHUNK as libc::c_int) };
+
+        Ok(Self {
+            read,
+            write,
+            usable: true,
+        })
+    }
+
+    /// False once the file turned out not to support `splice`. What was
+    /// already taken off the socket has been written to it regardless.
+    pub fn usable(&self) -> bool {
+        self.usable
+    }
+
+    /// Moves up to `len` bytes from `socket` into `file` at `offset`.
+    /// Returns 0 at the end of the stream. Doesn't move the file position.
+    pub fn splice(
+        &mut self,
+        socket: &Socket,
+        file: &File,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        use std::ptr;
+
+        // SAFETY: both descriptors are open for the duration of the call and
+        // the offset pointers are null.
+        let moved = unsafe {
+            libc::splice(
+                socket.as_raw_fd(),
+                ptr::null_mut(),
+                self.write.as_raw_fd(),
+                ptr::null_mut(),
+                len.min(CHUNK),
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+            )
+        };
+
+        if moved < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let moved = moved as usize;
+        let mut written = 0;
+
+        while written < moved {
+            let mut position = (offset + written as u64) as libc::loff_t;
+
+            // SAFETY: both descriptors are open for the duration of the call
+            // and `position` outlives it.
+            let n = unsafe {
+                libc::splice(
+                    self.read.as_raw_fd(),
+                    ptr::null_mut(),
+                    file.as_raw_fd(),
+                    &mut position,
+                    moved - written,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+
+            if n < 0 {
+                let err = io::Error::last_os_error();
+
+                if !is_unsupported(&err) {
+                    return Err(err);
+                }
+
+                self.usable = false;
+                self.drain(file, offset + written as u64, moved - written)?;
+                break;
+            }
+
+            written += n as usize;
+        }
+
+        Ok(moved)
+    }
+
+    /// Writes `len` bytes still in the pipe to `file` at `offset` the usual
+    /// way.
+    fn drain(&self, file: &File, offset: u64, len: usize) -> io::Result<()> {
+        use std::io::Read;
+        use std::os::unix::fs::FileExt;
+
+        let mut pipe = File::from(self.read.try_clone()?);
+        let mut buffer = vec![0u8; len];
+        pipe.read_exact(&mut buffer)?;
+
+        file.write_all_at(&buffer, offset)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Splice;
+
+#[cfg(not(target_os = "linux"))]
+impl Splice {
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn usable(&self) -> bool {
+        false
+    }
+
+    pub fn splice(
+        &mut self,
+        _socket: &Socket,
+        _file: &File,
+        _offset: u64,
+        _len: usize,
+    ) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}